@@ -0,0 +1,16 @@
+use std::process::Command;
+
+// exposes the git commit being built as GIT_COMMIT, when built from a checkout
+fn main() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    let output = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output();
+    if let Ok(output) = output {
+        if output.status.success() {
+            let commit = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            println!("cargo:rustc-env=GIT_COMMIT={}", commit);
+        }
+    }
+}
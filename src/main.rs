@@ -1,41 +1,755 @@
-use rocksdb::{Error, IteratorMode, DB};
-use serde_json::{json, Value};
-use std::collections::HashMap;
-use std::path::Path;
-use std::sync::Arc;
-use tokio;
+use rocksdb::{
+    ColumnFamilyDescriptor, DBCompactionStyle, Direction, IteratorMode, MergeOperands, Options,
+    WriteBatch, DB, DEFAULT_COLUMN_FAMILY_NAME,
+};
+use serde::Deserialize;
+use serde_json::{json, Number, Value};
+use sha1::{Digest, Sha1};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::convert::Infallible;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, IsTerminal};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
-use warp::http::StatusCode;
-use warp::{reply, Filter};
+use warp::http::{HeaderValue, Method, StatusCode};
+use warp::{reply, Filter, Reply};
+
+struct Config {
+    // number of tokio worker threads; defaults to one per cpu core
+    worker_threads: Option<usize>,
+    // `--read-only`: open rocksdb read-only and refuse all writes
+    read_only: bool,
+    // page size for searches without conditions, unless `limit` is given
+    default_list_limit: usize,
+    // key expected in `X-API-Key` by the /admin routes; unset disables them
+    admin_api_key: Option<String>,
+    log_format: LogFormat,
+    // searches and other full scans allowed to run at the same time
+    max_concurrent_scans: usize,
+    // origins allowed to call the read and the write routes; None allows any
+    cors_read_origins: Option<Vec<String>>,
+    cors_write_origins: Option<Vec<String>>,
+    // how long the ids matching a query are reused; None disables the cache
+    query_cache_ttl: Option<Duration>,
+    // tolerance of `=` between a float field and a float query value
+    float_epsilon: f64,
+    // requests per second and burst allowed per client ip; None disables the limit
+    rate_limit: Option<(f64, f64)>,
+    // `--trust-x-forwarded-for`: take the client ip from the header set by a proxy
+    trust_forwarded_for: bool,
+    // format of search results when neither `?format=` nor Accept picks one
+    default_format: ResultFormat,
+    // fields returned in search results without `?fields=`; None returns whole documents
+    default_fields: Option<Vec<String>>,
+    // order of search results without `?sort=`; None keeps key order
+    default_sort: Option<Sort>,
+    // files rocksdb keeps open per database; -1 keeps every file open
+    max_open_files: i32,
+    // longest path a query condition resolves, elemMatch included
+    max_path_depth: usize,
+    // splits request paths into object keys, so keys may contain dots
+    path_separator: char,
+    // longest key or value a query may contain, in bytes
+    max_value_length: usize,
+    // bytes of one memtable, and memtables kept before writes stall
+    write_buffer_size: Option<usize>,
+    max_write_buffer_number: Option<i32>,
+    // compaction tuning of both dbs; None keeps rocksdb's defaults
+    compaction_style: Option<DBCompactionStyle>,
+    num_levels: Option<i32>,
+    target_file_size_base: Option<u64>,
+    level0_compaction_trigger: Option<i32>,
+    // `--bulk-load`: no automatic compactions until POST /admin/compact
+    bulk_load: bool,
+    // `--soft-delete`: deleting keeps a `_deleted` tombstone instead of the key
+    soft_delete: bool,
+    // prior versions kept per document; 0 keeps none
+    history_depth: usize,
+    // path every route is mounted under, e.g. "/api/v1"; empty for the root
+    route_prefix: String,
+    // what POST /docs does with a document equal to one it already stored
+    duplicates: Duplicates,
+    // what every write adds to the audit log
+    audit_log: AuditLog,
+    // searches taking longer are logged at warn level; None logs none
+    slow_query: Option<Duration>,
+    // `--reject-full-scans`: searches the index can't narrow down get a 400
+    reject_full_scans: bool,
+    // `--reindex-on-start`: rebuild the posting lists from the documents before serving
+    reindex_on_start: bool,
+    // directories POST /admin/import-file may read from; empty disables it
+    import_dirs: Vec<PathBuf>,
+    // every address gets its own listener serving the same routes
+    listen_addrs: Vec<SocketAddr>,
+    // certificate and key every listener serves https with; None serves http
+    #[cfg(feature = "tls")]
+    tls: Option<TlsFiles>,
+    // search results stop growing past this many serialized bytes
+    max_response_bytes: usize,
+    // fields derived from others whenever a document is written, in order
+    computed_fields: Vec<ComputedField>,
+    // `--expose-internal-errors`: 500 responses carry the error instead of a generic message
+    expose_internal_errors: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Duplicates {
+    // store it again under a new id
+    Allow,
+    // answer 200 with the id of the stored one
+    Existing,
+    // answer 409
+    Reject,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum AuditLog {
+    Off,
+    // the time, id and operation of each write
+    On,
+    // also the merge patch from the old document to the new one
+    Diff,
+}
+
+enum LogFormat {
+    // one json object per line, for log collectors
+    Json,
+    // multi-line, human readable
+    Pretty,
+}
+
+// the pem contents of TLS_CERT_PATH and TLS_KEY_PATH
+#[cfg(feature = "tls")]
+struct TlsFiles {
+    cert: Vec<u8>,
+    key: Vec<u8>,
+}
+
+// the file at `path` if it holds a pem block whose label contains `label`,
+// e.g. `PRIVATE KEY` for pkcs#8, rsa and ec keys alike
+#[cfg(feature = "tls")]
+fn read_pem(var: &str, path: &str, label: &str) -> Vec<u8> {
+    let contents = fs::read(path)
+        .unwrap_or_else(|e| panic!("{} must be a readable file, {:?}: {}", var, path, e));
+    let text = String::from_utf8_lossy(&contents);
+    let has_block = text
+        .lines()
+        .any(|line| line.starts_with("-----BEGIN ") && line.contains(label));
+    if !has_block {
+        panic!(
+            "{} must be a PEM file with a {}, got {:?}",
+            var, label, path
+        );
+    }
+    contents
+}
+
+// `source:transform:target` of COMPUTED_FIELDS, e.g. `title:lowercase:title_lower`
+struct ComputedField {
+    source: Vec<String>,
+    transform: Transform,
+    target: Vec<String>,
+}
+
+#[derive(Clone, Copy)]
+enum Transform {
+    Lowercase,
+    Uppercase,
+    Trim,
+    // characters of a string, elements of an array
+    Length,
+}
+
+impl Transform {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "lowercase" => Some(Transform::Lowercase),
+            "uppercase" => Some(Transform::Uppercase),
+            "trim" => Some(Transform::Trim),
+            "length" => Some(Transform::Length),
+            _ => None,
+        }
+    }
+
+    // None when the transform doesn't apply to the value
+    fn apply(self, value: &Value) -> Option<Value> {
+        match self {
+            Transform::Lowercase => Some(Value::from(value.as_str()?.to_lowercase())),
+            Transform::Uppercase => Some(Value::from(value.as_str()?.to_uppercase())),
+            Transform::Trim => Some(Value::from(value.as_str()?.trim())),
+            Transform::Length => value_length(value).map(Value::from),
+        }
+    }
+}
+
+impl ComputedField {
+    fn parse(rule: &str, separator: char) -> Option<Self> {
+        let path = |path: &str| {
+            let parts = path
+                .split(separator)
+                .map(|s| s.trim().to_string())
+                .collect::<Vec<String>>();
+            Some(parts).filter(|parts| parts.iter().all(|p| !p.is_empty()))
+        };
+        match rule.split(':').collect::<Vec<&str>>()[..] {
+            [source, transform, target] => Some(Self {
+                source: path(source)?,
+                transform: Transform::from_name(transform.trim())?,
+                target: path(target)?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+// a target whose source is missing, or doesn't fit the transform, is
+// removed, so it never keeps the value of an older version
+fn apply_computed_fields(doc: &mut Value, rules: &[ComputedField]) {
+    for rule in rules {
+        let source = get_value_from_doc(doc.clone(), &rule.source);
+        match rule.transform.apply(&source) {
+            Some(value) => {
+                set_value_in_doc(doc, &rule.target, value);
+            }
+            None => remove_value_in_doc(doc, &rule.target),
+        }
+    }
+}
+
+impl Config {
+    // settings come from environment variables, switches from the command line
+    fn load() -> Self {
+        let worker_threads = env::var("WORKER_THREADS")
+            .ok()
+            .map(|v| match v.parse::<usize>() {
+                Ok(n) if n > 0 => n,
+                _ => panic!("WORKER_THREADS must be a positive integer, got {:?}", v),
+            });
+        let read_only = env::args().any(|arg| arg == "--read-only");
+        let default_list_limit = env::var("DEFAULT_LIST_LIMIT")
+            .ok()
+            .map(|v| match v.parse::<usize>() {
+                Ok(n) => n,
+                _ => panic!(
+                    "DEFAULT_LIST_LIMIT must be a non-negative integer, got {:?}",
+                    v
+                ),
+            })
+            .unwrap_or(100);
+        let admin_api_key = env::var("ADMIN_API_KEY").ok().filter(|v| !v.is_empty());
+        let log_format = match env::var("LOG_FORMAT").ok().as_deref() {
+            Some("json") => LogFormat::Json,
+            Some("pretty") => LogFormat::Pretty,
+            // humans read terminals, everything else is probably a log collector
+            None if std::io::stdout().is_terminal() => LogFormat::Pretty,
+            None => LogFormat::Json,
+            Some(v) => panic!("LOG_FORMAT must be `json` or `pretty`, got {:?}", v),
+        };
+        let max_concurrent_scans = env::var("MAX_CONCURRENT_SCANS")
+            .ok()
+            .map(|v| match v.parse::<usize>() {
+                Ok(n) if n > 0 => n,
+                _ => panic!(
+                    "MAX_CONCURRENT_SCANS must be a positive integer, got {:?}",
+                    v
+                ),
+            })
+            // scans run on the worker threads, so more than that only queue up
+            .or(worker_threads)
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+        // a comma-separated list of origins, or `*` for any
+        let origins = |name: &str| {
+            env::var(name).ok().filter(|v| v.trim() != "*").map(|v| {
+                v.split(',')
+                    .map(|origin| origin.trim().to_string())
+                    .filter(|origin| !origin.is_empty())
+                    .collect::<Vec<String>>()
+            })
+        };
+        let cors_read_origins = origins("CORS_READ_ORIGINS");
+        let cors_write_origins = origins("CORS_WRITE_ORIGINS");
+        let query_cache_ttl = env::var("QUERY_CACHE_TTL_MS")
+            .ok()
+            .map(|v| match v.parse::<u64>() {
+                Ok(ms) => ms,
+                _ => panic!(
+                    "QUERY_CACHE_TTL_MS must be a non-negative integer, got {:?}",
+                    v
+                ),
+            })
+            .filter(|ms| *ms > 0)
+            .map(Duration::from_millis);
+        let slow_query = env::var("SLOW_QUERY_MS")
+            .ok()
+            .map(|v| match v.parse::<u64>() {
+                Ok(ms) => ms,
+                _ => panic!("SLOW_QUERY_MS must be a non-negative integer, got {:?}", v),
+            })
+            .map(Duration::from_millis);
+        let float_epsilon = env::var("FLOAT_EPSILON")
+            .ok()
+            .map(|v| match v.parse::<f64>() {
+                Ok(e) if e >= 0.0 => e,
+                _ => panic!("FLOAT_EPSILON must be a non-negative number, got {:?}", v),
+            })
+            .unwrap_or(DEFAULT_FLOAT_EPSILON);
+        let positive = |name: &str| {
+            env::var(name).ok().map(|v| match v.parse::<f64>() {
+                Ok(n) if n > 0.0 => n,
+                _ => panic!("{} must be a positive number, got {:?}", name, v),
+            })
+        };
+        // the burst defaults to one second worth of requests
+        let rate_limit = positive("RATE_LIMIT_PER_SEC")
+            .map(|rate| (rate, positive("RATE_LIMIT_BURST").unwrap_or(rate.max(1.0))));
+        let trust_forwarded_for = env::args().any(|arg| arg == "--trust-x-forwarded-for");
+        let default_format = match env::var("DEFAULT_FORMAT") {
+            Ok(v) => ResultFormat::from_name(&v).unwrap_or_else(|| {
+                panic!(
+                    "DEFAULT_FORMAT must be `json`, `ndjson` or `csv`, got {:?}",
+                    v
+                )
+            }),
+            Err(_) => ResultFormat::Json,
+        };
+        // `,` separates fields and `:` the parts of a computed field
+        let path_separator = env::var("PATH_SEPARATOR")
+            .map(|v| {
+                let allowed = |c: char| {
+                    !c.is_alphanumeric() && !c.is_whitespace() && !matches!(c, ',' | ':' | '"')
+                };
+                match v.chars().collect::<Vec<char>>()[..] {
+                    [c] if allowed(c) => c,
+                    _ => panic!(
+                        "PATH_SEPARATOR must be one punctuation character, got {:?}",
+                        v
+                    ),
+                }
+            })
+            .unwrap_or('.');
+        let default_fields = env::var("DEFAULT_FIELDS")
+            .ok()
+            .and_then(|v| parse_fields(&v));
+        let default_sort = env::var("DEFAULT_SORT")
+            .ok()
+            .filter(|v| !v.trim().is_empty())
+            .map(|v| match Sort::parse(&v, path_separator) {
+                Some(sort) => sort,
+                None => panic!("DEFAULT_SORT must be a field or -field, got {:?}", v),
+            });
+        let max_open_files = env::var("MAX_OPEN_FILES")
+            .ok()
+            .map(|v| match v.parse::<i32>() {
+                Ok(n) if n == -1 || n > 0 => n,
+                _ => panic!(
+                    "MAX_OPEN_FILES must be a positive integer or -1, got {:?}",
+                    v
+                ),
+            })
+            .unwrap_or(-1);
+        let max_path_depth = env::var("MAX_PATH_DEPTH")
+            .ok()
+            .map(|v| match v.parse::<usize>() {
+                Ok(n) if n > 0 => n,
+                _ => panic!("MAX_PATH_DEPTH must be a positive integer, got {:?}", v),
+            })
+            .unwrap_or(DEFAULT_MAX_PATH_DEPTH);
+        let max_value_length = env::var("MAX_QUERY_VALUE_LENGTH")
+            .ok()
+            .map(|v| match v.parse::<usize>() {
+                Ok(n) if n > 0 => n,
+                _ => panic!(
+                    "MAX_QUERY_VALUE_LENGTH must be a positive integer, got {:?}",
+                    v
+                ),
+            })
+            .unwrap_or(DEFAULT_MAX_VALUE_LENGTH);
+        let write_buffer_size =
+            env::var("WRITE_BUFFER_SIZE")
+                .ok()
+                .map(|v| match v.parse::<usize>() {
+                    Ok(n) if n > 0 => n,
+                    _ => panic!("WRITE_BUFFER_SIZE must be a positive integer, got {:?}", v),
+                });
+        let max_write_buffer_number =
+            env::var("MAX_WRITE_BUFFER_NUMBER")
+                .ok()
+                .map(|v| match v.parse::<i32>() {
+                    Ok(n) if n > 0 => n,
+                    _ => panic!(
+                        "MAX_WRITE_BUFFER_NUMBER must be a positive integer, got {:?}",
+                        v
+                    ),
+                });
+        let compaction_style = env::var("COMPACTION_STYLE").ok().map(|v| match v.as_str() {
+            "level" => DBCompactionStyle::Level,
+            "universal" => DBCompactionStyle::Universal,
+            _ => panic!(
+                "COMPACTION_STYLE must be `level` or `universal`, got {:?}",
+                v
+            ),
+        });
+        let num_levels = env::var("NUM_LEVELS").ok().map(|v| match v.parse::<i32>() {
+            Ok(n) if (1..=MAX_NUM_LEVELS).contains(&n) => n,
+            _ => panic!(
+                "NUM_LEVELS must be an integer from 1 to {}, got {:?}",
+                MAX_NUM_LEVELS, v
+            ),
+        });
+        let target_file_size_base =
+            env::var("TARGET_FILE_SIZE_BASE")
+                .ok()
+                .map(|v| match v.parse::<u64>() {
+                    Ok(n) if n >= MIN_TARGET_FILE_SIZE => n,
+                    _ => panic!(
+                        "TARGET_FILE_SIZE_BASE must be at least {} bytes, got {:?}",
+                        MIN_TARGET_FILE_SIZE, v
+                    ),
+                });
+        let level0_compaction_trigger = env::var("LEVEL0_COMPACTION_TRIGGER").ok().map(|v| match v
+            .parse::<i32>(
+        ) {
+            Ok(n) if n > 0 => n,
+            _ => panic!(
+                "LEVEL0_COMPACTION_TRIGGER must be a positive integer, got {:?}",
+                v
+            ),
+        });
+        let bulk_load = env::args().any(|arg| arg == "--bulk-load");
+        let soft_delete = env::args().any(|arg| arg == "--soft-delete");
+        let reject_full_scans = env::args().any(|arg| arg == "--reject-full-scans");
+        let reindex_on_start = env::args().any(|arg| arg == "--reindex-on-start");
+        let expose_internal_errors = env::args().any(|arg| arg == "--expose-internal-errors");
+        // resolved once, so symlinks and `..` in a requested path can't escape them
+        let import_dirs = env::var("IMPORT_DIRS")
+            .map(|v| {
+                v.split(',')
+                    .map(|dir| dir.trim())
+                    .filter(|dir| !dir.is_empty())
+                    .map(|dir| {
+                        fs::canonicalize(dir)
+                            .unwrap_or_else(|e| panic!("IMPORT_DIRS: {:?}: {}", dir, e))
+                    })
+                    .collect::<Vec<PathBuf>>()
+            })
+            .unwrap_or_default();
+        let max_response_bytes = env::var("MAX_RESPONSE_BYTES")
+            .ok()
+            .map(|v| match v.parse::<usize>() {
+                Ok(n) if n > 0 => n,
+                _ => panic!("MAX_RESPONSE_BYTES must be a positive integer, got {:?}", v),
+            })
+            .unwrap_or(DEFAULT_MAX_RESPONSE_BYTES);
+        let computed_fields = env::var("COMPUTED_FIELDS")
+            .map(|v| {
+                v.split(',')
+                    .filter(|rule| !rule.trim().is_empty())
+                    .map(|rule| {
+                        ComputedField::parse(rule, path_separator).unwrap_or_else(|| {
+                            panic!(
+                                "COMPUTED_FIELDS must list source:transform:target rules, got {:?}",
+                                rule
+                            )
+                        })
+                    })
+                    .collect::<Vec<ComputedField>>()
+            })
+            .unwrap_or_default();
+        let listen_addrs = env::var("LISTEN_ADDRS")
+            .map(|v| {
+                v.split(',')
+                    .map(|addr| addr.trim())
+                    .filter(|addr| !addr.is_empty())
+                    .map(|addr| {
+                        addr.parse::<SocketAddr>().unwrap_or_else(|_| {
+                            panic!("LISTEN_ADDRS must list socket addresses, got {:?}", addr)
+                        })
+                    })
+                    .collect::<Vec<SocketAddr>>()
+            })
+            .ok()
+            .filter(|addrs| !addrs.is_empty())
+            .unwrap_or_else(|| vec![SocketAddr::from(([127, 0, 0, 1], 8080))]);
+        // read once here, so a missing or non-pem file fails before listening
+        let tls_paths = (
+            env::var("TLS_CERT_PATH").ok(),
+            env::var("TLS_KEY_PATH").ok(),
+        );
+        if tls_paths.0.is_some() != tls_paths.1.is_some() {
+            panic!("TLS_CERT_PATH and TLS_KEY_PATH must be set together");
+        }
+        if cfg!(not(feature = "tls")) && tls_paths.0.is_some() {
+            panic!("TLS_CERT_PATH needs a build with `--features tls`");
+        }
+        #[cfg(feature = "tls")]
+        let tls = match tls_paths {
+            (Some(cert), Some(key)) => Some(TlsFiles {
+                cert: read_pem("TLS_CERT_PATH", &cert, "CERTIFICATE"),
+                key: read_pem("TLS_KEY_PATH", &key, "PRIVATE KEY"),
+            }),
+            _ => None,
+        };
+        let history_depth = env::var("HISTORY_DEPTH")
+            .ok()
+            .map(|v| match v.parse::<usize>() {
+                Ok(n) => n,
+                _ => panic!("HISTORY_DEPTH must be a non-negative integer, got {:?}", v),
+            })
+            .unwrap_or(0);
+        // "api/v1/", "/api//v1" and "/api/v1" are the same prefix
+        let route_prefix = env::var("ROUTE_PREFIX")
+            .map(|v| canonical_path(&v))
+            .unwrap_or_default();
+        let route_prefix = if route_prefix == "/" {
+            String::new()
+        } else {
+            route_prefix
+        };
+        let duplicates = match env::var("DUPLICATES").ok().as_deref() {
+            None | Some("allow") => Duplicates::Allow,
+            Some("existing") => Duplicates::Existing,
+            Some("reject") => Duplicates::Reject,
+            Some(v) => panic!(
+                "DUPLICATES must be `allow`, `existing` or `reject`, got {:?}",
+                v
+            ),
+        };
+        let audit_log = match env::var("AUDIT_LOG").ok().as_deref() {
+            None | Some("off") => AuditLog::Off,
+            Some("on") => AuditLog::On,
+            Some("diff") => AuditLog::Diff,
+            Some(v) => panic!("AUDIT_LOG must be `off`, `on` or `diff`, got {:?}", v),
+        };
+        Self {
+            worker_threads,
+            read_only,
+            default_list_limit,
+            admin_api_key,
+            log_format,
+            max_concurrent_scans,
+            cors_read_origins,
+            cors_write_origins,
+            query_cache_ttl,
+            float_epsilon,
+            rate_limit,
+            trust_forwarded_for,
+            default_format,
+            default_fields,
+            default_sort,
+            max_open_files,
+            max_path_depth,
+            path_separator,
+            max_value_length,
+            write_buffer_size,
+            max_write_buffer_number,
+            compaction_style,
+            num_levels,
+            target_file_size_base,
+            level0_compaction_trigger,
+            bulk_load,
+            soft_delete,
+            history_depth,
+            route_prefix,
+            duplicates,
+            audit_log,
+            slow_query,
+            reject_full_scans,
+            reindex_on_start,
+            import_dirs,
+            listen_addrs,
+            #[cfg(feature = "tls")]
+            tls,
+            max_response_bytes,
+            computed_fields,
+            expose_internal_errors,
+        }
+    }
+}
 
 struct Server {
+    db_path: PathBuf,
     docs: DB,
     index_db: DB,
-    port: String,
+    config: Config,
+    // per-document locks for read-modify-write operations, see `lock_shard`
+    locks: Vec<tokio::sync::Mutex<()>>,
+    // bounds concurrent scans, see `Config::max_concurrent_scans`
+    scan_permits: Arc<tokio::sync::Semaphore>,
+    // set while maintenance (a compaction) runs, so /readyz reports not ready
+    maintenance: AtomicBool,
+    // automatic compactions are off until the next POST /admin/compact
+    bulk_load: AtomicBool,
+    // normalized query -> (when it ran, ids of the matching documents)
+    query_cache: Mutex<HashMap<String, (Instant, Vec<String>)>>,
+    // token bucket per client ip: (tokens left, when they were counted)
+    rate_limits: Mutex<HashMap<IpAddr, (f64, Instant)>>,
+    // orders the audit entries written within the same millisecond
+    audit_sequence: AtomicU64,
 }
 
+// clients tracked by the rate limiter before idle ones are dropped
+const RATE_LIMIT_CLIENTS: usize = 10_000;
+
+// shards of the per-document locks
+const LOCK_SHARDS: usize = 256;
+
+// column family of the documents db holding the audit log
+const AUDIT_CF: &str = "audit";
+
+// audit entries GET /admin/audit returns without `?limit=`
+const DEFAULT_AUDIT_LIMIT: usize = 1000;
+
+// queries kept in the cache before stale ones are dropped
+const QUERY_CACHE_CAPACITY: usize = 1024;
+
+// bounds of the compaction settings; rocksdb accepts more, but hardly usefully
+const MAX_NUM_LEVELS: i32 = 20;
+const MIN_TARGET_FILE_SIZE: u64 = 1 << 20;
+
+// 256 MiB of search results; far more than a page is meant to hold
+const DEFAULT_MAX_RESPONSE_BYTES: usize = 256 << 20;
+
+// documents /docs/schema looks at without `?sample=`
+const DEFAULT_SCHEMA_SAMPLE: usize = 1000;
+
 impl Server {
-    pub fn new(db_name: &str, port: &str) -> Result<Self, Error> {
+    pub fn new(db_name: &str, config: Config) -> Result<Self, ServerError> {
         let db_path = Path::new(db_name);
-        let docs = DB::open_default(db_path)?;
+        let bulk_load = config.bulk_load && !config.read_only;
+        let mut options = Options::default();
+        let mut index_options = Options::default();
+        let mut audit_options = Options::default();
+        for options in [&mut options, &mut index_options, &mut audit_options] {
+            options.create_if_missing(true);
+            options.set_max_open_files(config.max_open_files);
+            if let Some(size) = config.write_buffer_size {
+                options.set_write_buffer_size(size);
+            }
+            if let Some(number) = config.max_write_buffer_number {
+                options.set_max_write_buffer_number(number);
+            }
+            if let Some(style) = config.compaction_style {
+                options.set_compaction_style(style);
+            }
+            if let Some(levels) = config.num_levels {
+                options.set_num_levels(levels);
+            }
+            if let Some(size) = config.target_file_size_base {
+                options.set_target_file_size_base(size);
+            }
+            if let Some(trigger) = config.level0_compaction_trigger {
+                options.set_level_zero_file_num_compaction_trigger(trigger);
+            }
+            options.set_disable_auto_compactions(bulk_load);
+        }
+        // also needed read-only, to resolve pending merge operands on reads
+        options.set_merge_operator("counter", counter_full_merge, counter_partial_merge);
+        // the audit log is a column family of the documents db, so an entry goes
+        // in the same batch as its change. once created it's always opened, which
+        // rocksdb requires, and stays readable after AUDIT_LOG is turned off
+        let audit = config.audit_log != AuditLog::Off && !config.read_only
+            || DB::list_cf(&options, db_path).is_ok_and(|cfs| cfs.iter().any(|cf| cf == AUDIT_CF));
+        options.create_missing_column_families(true);
+        let mut column_families = vec![ColumnFamilyDescriptor::new(
+            DEFAULT_COLUMN_FAMILY_NAME,
+            options.clone(),
+        )];
+        if audit {
+            column_families.push(ColumnFamilyDescriptor::new(AUDIT_CF, audit_options));
+        }
         let index_path = db_path.with_extension("index");
-        let index_db = DB::open_default(index_path)?;
+        let (docs, index_db) = if config.read_only {
+            (
+                DB::open_cf_descriptors_read_only(&options, db_path, column_families, false)?,
+                DB::open_for_read_only(&index_options, index_path, false)?,
+            )
+        } else {
+            (
+                DB::open_cf_descriptors(&options, db_path, column_families)?,
+                DB::open(&index_options, index_path)?,
+            )
+        };
 
+        let scan_permits = Arc::new(tokio::sync::Semaphore::new(config.max_concurrent_scans));
         Ok(Self {
-            docs: docs,
-            index_db: index_db,
-            port: port.to_string(),
+            db_path: db_path.to_path_buf(),
+            docs,
+            index_db,
+            config,
+            locks: (0..LOCK_SHARDS)
+                .map(|_| tokio::sync::Mutex::new(()))
+                .collect(),
+            scan_permits,
+            maintenance: AtomicBool::new(false),
+            bulk_load: AtomicBool::new(bulk_load),
+            query_cache: Mutex::new(HashMap::new()),
+            rate_limits: Mutex::new(HashMap::new()),
+            audit_sequence: AtomicU64::new(0),
         })
     }
 
-    async fn index(self: Arc<Self>, db: &DB, id: String, document: Value) {
+    // an id always hashes to the same shard, so operations on one document
+    // serialize while different documents mostly proceed in parallel
+    fn lock_shard(&self, id: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        id.hash(&mut hasher);
+        (hasher.finish() % LOCK_SHARDS as u64) as usize
+    }
+
+    async fn lock(&self, id: &str) -> tokio::sync::MutexGuard<'_, ()> {
+        self.locks[self.lock_shard(id)].lock().await
+    }
+
+    // take a token from the bucket of `ip`, or tell how long until there is one
+    fn take_token(&self, ip: IpAddr) -> Result<(), Duration> {
+        let (rate, burst) = match self.config.rate_limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let now = Instant::now();
+        let mut buckets = self.rate_limits.lock().unwrap();
+        // forget clients whose bucket has refilled completely
+        if buckets.len() >= RATE_LIMIT_CLIENTS {
+            buckets.retain(|_, (tokens, at)| {
+                *tokens + now.duration_since(*at).as_secs_f64() * rate < burst
+            });
+        }
+        let (tokens, at) = buckets.entry(ip).or_insert((burst, now));
+        *tokens = (*tokens + now.duration_since(*at).as_secs_f64() * rate).min(burst);
+        *at = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - *tokens) / rate))
+        }
+    }
+
+    async fn index(
+        self: Arc<Self>,
+        db: &DB,
+        id: String,
+        document: Value,
+    ) -> Result<(), ServerError> {
         let path_values = get_path_values(&document, "".to_string());
 
         for path_value in path_values {
             let index_key = path_value.clone();
             // read existing posting list
             let read_options = rocksdb::ReadOptions::default();
-            let ids = db.get_opt(index_key.clone(), &read_options).unwrap();
+            let ids = db.get_opt(index_key.clone(), &read_options)?;
             match ids {
                 None => {
                     // create new entry
@@ -43,11 +757,11 @@ impl Server {
                     let ids = ids.join(",");
                     // write to db
                     let write_options = rocksdb::WriteOptions::default();
-                    db.put_opt(index_key, ids, &write_options).unwrap();
+                    db.put_opt(index_key, ids, &write_options)?;
                 }
                 Some(ids) => {
                     // append to existing entry
-                    let ids = String::from_utf8(ids).unwrap();
+                    let ids = String::from_utf8(ids)?;
                     let ids = ids.split(",").collect::<Vec<&str>>();
                     let mut ids = ids.iter().map(|s| s.to_string()).collect::<Vec<String>>();
                     // add new entry
@@ -55,370 +769,5524 @@ impl Server {
                     let ids = ids.join(",");
                     // write to db
                     let write_options = rocksdb::WriteOptions::default();
-                    db.put_opt(index_key, ids, &write_options).unwrap();
+                    db.put_opt(index_key, ids, &write_options)?;
                 }
             }
         }
+        Ok(())
     }
 
-    async fn add_document(
+    async fn unindex(
         self: Arc<Self>,
+        db: &DB,
+        id: String,
         document: Value,
+    ) -> Result<(), ServerError> {
+        let path_values = get_path_values(&document, "".to_string());
+
+        for index_key in path_values {
+            let ids = db.get(index_key.clone())?;
+            if let Some(ids) = ids {
+                // remove the id from the posting list
+                let ids = String::from_utf8(ids)?;
+                let ids = ids.split(',').filter(|s| *s != id).collect::<Vec<&str>>();
+                let write_options = rocksdb::WriteOptions::default();
+                if ids.is_empty() {
+                    db.delete_opt(index_key, &write_options)?;
+                } else {
+                    db.put_opt(index_key, ids.join(","), &write_options)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn add_document(
+        self: Arc<Self>,
+        mut document: Value,
+        preference: Option<String>,
     ) -> Result<impl warp::Reply, warp::Rejection> {
+        // queries, patches and the metadata fields all expect an object
+        if !document.is_object() {
+            return Ok(ApiError::bad_request("document must be a JSON object").into_response());
+        }
+        apply_computed_fields(&mut document, &self.config.computed_fields);
+        // `?return=representation` sends the stored document back, timestamps included
+        let representation = match preference.as_deref() {
+            None | Some("minimal") => false,
+            Some("representation") => true,
+            Some(_) => {
+                let error = ApiError::bad_request("return must be `minimal` or `representation`");
+                return Ok(error.into_response());
+            }
+        };
+        // equal documents hash the same, and the guard keeps two of them
+        // from being inserted side by side
+        let (hash_key, _guard) = match self.config.duplicates {
+            Duplicates::Allow => (None, None),
+            duplicates => {
+                let hash_key = content_hash_key(&document);
+                let guard = self.lock(&hash_key).await;
+                if let Some(id) = self.find_duplicate(&hash_key)? {
+                    if duplicates == Duplicates::Reject {
+                        let message = format!("an equal document exists: {}", id);
+                        return Ok(ApiError::new(StatusCode::CONFLICT, message).into_response());
+                    }
+                    let status = StatusCode::OK;
+                    let mut body =
+                        json!({ "id": id, "status": status.as_str(), "duplicate": true });
+                    if representation {
+                        body["document"] = self.get_document_by_id(id)?.unwrap_or(Value::Null);
+                    }
+                    return Ok(reply::with_status(reply::json(&body), status).into_response());
+                }
+                (Some(hash_key), Some(guard))
+            }
+        };
         let id = Uuid::new_v4().to_string();
+        stamp_document(&mut document, None);
         let server_clone = Arc::clone(&self);
         // indexing
-        self.index(&server_clone.index_db, id.clone(), document.clone())
-            .await;
-        let doc = serde_json::to_string(&document).unwrap();
+        Arc::clone(&self)
+            .index(&server_clone.index_db, id.clone(), document.clone())
+            .await?;
+        // without serde_json's `preserve_order` feature objects are BTreeMaps, so
+        // keys always serialize sorted and equal documents are stored the same
+        let doc = serde_json::to_string(&document).map_err(ServerError::from)?;
         // write to db
+        let mut batch = WriteBatch::default();
+        batch.put(&id, doc);
+        server_clone.audit(&mut batch, &id, None, Some(&document));
         let write_options = rocksdb::WriteOptions::default();
         server_clone
             .docs
-            .put_opt(id.clone(), doc, &write_options)
-            .unwrap();
+            .write_opt(batch, &write_options)
+            .map_err(ServerError::from)?;
+        if let Some(hash_key) = hash_key {
+            server_clone
+                .index_db
+                .put_opt(hash_key, id.clone(), &write_options)
+                .map_err(ServerError::from)?;
+        }
+        server_clone.invalidate_query_cache();
         // response
         let status = StatusCode::CREATED;
-        let response = reply::json(&json!({ "id": id, "status": status.as_str()}));
-        Ok(reply::with_status(response, status))
+        let mut body = json!({ "id": id, "status": status.as_str()});
+        if representation {
+            body["document"] = document;
+        }
+        Ok(reply::with_status(reply::json(&body), status).into_response())
     }
 
-    async fn get_document(
+    async fn delete_documents(
         self: Arc<Self>,
-        id: String,
+        ids: Vec<String>,
     ) -> Result<impl warp::Reply, warp::Rejection> {
-        // read from db
-        let doc = self.get_document_by_id(id).unwrap();
-        let doc = json!(doc);
-        // response
-        let status = if doc.is_null() {
-            StatusCode::NOT_FOUND
-        } else {
-            StatusCode::OK
-        };
-        let body = json!({ "status": status.as_str(), "doc": doc });
-        let response = reply::json(&body);
-        Ok(reply::with_status(response, status))
-    }
-    // helper
-    fn get_document_by_id(&self, id: String) -> Result<HashMap<String, String>, Error> {
-        let read_options = rocksdb::ReadOptions::default();
-        let doc = self.docs.get_opt(id, &read_options).unwrap();
-        // make it to string
-        let doc = String::from_utf8(doc.unwrap()).unwrap();
-        // convert to json
-        let doc: HashMap<String, String> = serde_json::from_str(&doc).unwrap();
-        Ok(doc)
-    }
+        // shards are locked in ascending order, so two deletes can't deadlock
+        let shards = ids
+            .iter()
+            .map(|id| self.lock_shard(id))
+            .collect::<BTreeSet<usize>>();
+        let mut guards = Vec::with_capacity(shards.len());
+        for shard in shards {
+            guards.push(self.locks[shard].lock().await);
+        }
 
-    async fn search_documents(
-        self: Arc<Self>,
-        q: &String,
-    ) -> Result<impl warp::Reply, warp::Rejection> {
-        let query = match parse_query(q) {
-            Ok(q) => q,
-            Err(e) => {
-                return Ok(warp::reply::with_status(
-                    format!("Invalid query: {}", e),
-                    StatusCode::BAD_REQUEST,
-                ))
+        let mut batch = WriteBatch::default();
+        let mut deleted = Vec::new();
+        let mut not_found = Vec::new();
+        let mut seen = HashSet::new();
+        for id in ids {
+            if !seen.insert(id.clone()) {
+                continue;
             }
-        };
-
-        let mut documents = Vec::new();
-
-        // lookup index
-        let mut is_range = false;
-        let mut doc2cnt = HashMap::new();
-        let mut non_range_args = 0;
-        for cond in query.conditions.iter() {
-            if cond.op == "=".to_string() {
-                non_range_args += 1;
-                let index_key = cond.key.clone() + ":" + &cond.value;
-                let ids = self.index_db.get(index_key).unwrap();
-                match ids {
-                    None => {
-                        // no match
-                        continue;
-                    }
-                    Some(ids) => {
-                        // match
-                        let ids = String::from_utf8(ids).unwrap();
-                        let ids: Vec<&str> = ids.split(",").collect();
-                        let ids = ids.iter().map(|s| s.to_string()).collect::<Vec<String>>();
-                        for id in ids {
-                            let cnt = doc2cnt.entry(id).or_insert(0);
-                            *cnt += 1;
-                        }
+            match self.get_live_document(id.clone())? {
+                Some(doc) => {
+                    self.record_version(&id, &doc)?;
+                    if self.config.soft_delete {
+                        let tombstone = tombstone_of(&doc);
+                        batch.put(id.clone(), tombstone.to_string());
+                        self.audit(&mut batch, &id, Some(&doc), Some(&tombstone));
+                    } else {
+                        batch.delete(id.clone());
+                        self.audit(&mut batch, &id, Some(&doc), None);
                     }
+                    deleted.push((id, doc));
                 }
-            } else {
-                is_range = true;
+                None => not_found.push(id),
             }
         }
+        // all documents go in one atomic write
+        let write_options = rocksdb::WriteOptions::default();
+        self.docs
+            .write_opt(batch, &write_options)
+            .map_err(ServerError::from)?;
+        self.invalidate_query_cache();
+        let deleted_count = deleted.len();
+        // tombstones aren't indexed either, lookups only find live documents
+        for (id, doc) in deleted {
+            let server_clone = Arc::clone(&self);
+            Arc::clone(&self)
+                .unindex(&server_clone.index_db, id, doc)
+                .await?;
+        }
+
+        let status = StatusCode::OK;
+        let body = json!({
+            "status": status.as_str(),
+            "deleted": deleted_count,
+            "not_found": not_found.len(),
+            "not_found_ids": not_found,
+        });
+        Ok(reply::with_status(reply::json(&body), status))
+    }
 
-        let mut ids_in_all = Vec::new();
-        for (id, cnt) in doc2cnt {
-            if cnt == non_range_args {
-                ids_in_all.push(id);
+    async fn incr_document(
+        self: Arc<Self>,
+        id: String,
+        request: IncrRequest,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        // the merge alone wouldn't need it, but the check, the history and the
+        // reindex read the document around it
+        let guard = self.lock(&id).await;
+        let result = Arc::clone(&self).incr_document_by_merge(id, request).await;
+        drop(guard);
+        match result {
+            Ok(mut body) => {
+                let status = StatusCode::OK;
+                body["status"] = json!(status.as_str());
+                Ok(reply::with_status(reply::json(&body), status).into_response())
             }
+            Err(e) => Ok(e.into_response()),
         }
+    }
 
-        if ids_in_all.len() > 0 {
-            for id in ids_in_all {
-                let doc = self.get_document_by_id(id.clone()).unwrap();
-                let doc = json!(doc.clone());
-                if !is_range || query.matches(&doc) {
-                    documents.push(json!({
-                        "id": id,
-                        "body": doc,
-                    }));
-                }
+    // called with the lock of `id` held, for the check, the history and the
+    // reindex around the merge; the addition itself happens in rocksdb's merge operator
+    async fn incr_document_by_merge(
+        self: Arc<Self>,
+        id: String,
+        request: IncrRequest,
+    ) -> Result<Value, ApiError> {
+        let old = match self.get_live_document(id.clone())? {
+            Some(doc) => doc,
+            None => return Err(ApiError::not_found("document not found")),
+        };
+        let parts = split_path(&request.field, self.config.path_separator);
+        // the merge operator would skip these, so tell the client up front
+        match get_value_from_doc(old.clone(), &parts) {
+            Value::Null => {}
+            Value::Number(n) if add_numbers(&n, &request.by).is_some() => {}
+            Value::Number(n) => {
+                return Err(ApiError::bad_request(format!(
+                    "cannot add {} to {}",
+                    request.by, n
+                )))
             }
-        } else {
-            for entry in self.docs.iterator(IteratorMode::Start) {
-                match entry {
-                    Ok((key, value)) => {
-                        let document = match serde_json::from_slice(&value) {
-                            Ok(doc) => doc,
-                            Err(e) => {
-                                return Ok(warp::reply::with_status(
-                                    format!("Error deserializing document: {:?}", e),
-                                    StatusCode::INTERNAL_SERVER_ERROR,
-                                ))
-                            }
-                        };
-
-                        if query.matches(&document) {
-                            documents.push(json!({
-                                "id": String::from_utf8(key.to_vec()).unwrap(),
-                                "body": document,
-                            }));
-                        }
-                    }
-                    Err(e) => {
-                        return Ok(warp::reply::with_status(
-                            format!("Database error: {:?}", e),
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                        ))
-                    }
-                }
+            _ => {
+                return Err(ApiError::new(
+                    StatusCode::CONFLICT,
+                    format!("field {} is not a number", request.field),
+                ))
             }
         }
 
-        let response = json!({
-            "documents": documents,
-            "count": documents.len(),
+        self.record_version(&id, &old)?;
+        // the path is sent split, since the merge operator can't see the separator
+        let operand = json!({
+            "field": request.field,
+            "path": parts,
+            "by": request.by,
+            // the time travels with the operand, so every merge of it agrees on it
+            "_updated_at": format_rfc3339(now_millis()),
         });
-        Ok(warp::reply::with_status(
-            response.to_string(),
-            StatusCode::OK,
-        ))
+        // the lock keeps other increments out, so the merge will give `expected`
+        let mut expected = old.clone();
+        apply_incr(&mut expected, &operand);
+        let mut batch = WriteBatch::default();
+        batch.merge(&id, operand.to_string());
+        self.audit(&mut batch, &id, Some(&old), Some(&expected));
+        let write_options = rocksdb::WriteOptions::default();
+        self.docs
+            .write_opt(batch, &write_options)
+            .map_err(ServerError::from)?;
+        self.invalidate_query_cache();
+
+        // read back the merged document, which the lock makes `expected`
+        let new = match self.get_document_by_id(id.clone())? {
+            Some(doc) => doc,
+            None => return Err(ApiError::not_found("document not found")),
+        };
+        let value = get_value_from_doc(new.clone(), &parts);
+        // reindex
+        let server_clone = Arc::clone(&self);
+        Arc::clone(&self)
+            .unindex(&server_clone.index_db, id.clone(), old)
+            .await?;
+        self.index(&server_clone.index_db, id.clone(), new).await?;
+
+        Ok(json!({ "id": id, "field": request.field, "value": value }))
     }
-}
 
-fn get_value_from_doc(doc: Value, parts: &[String]) -> Value {
-    let mut current = &doc;
+    async fn patch_document(
+        self: Arc<Self>,
+        id: String,
+        mut patch: Value,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        // a non-object patch would replace the whole document with a non-object
+        let unset = match patch.as_object_mut() {
+            Some(patch) => patch.remove("$unset"),
+            None => return Ok(ApiError::bad_request("patch must be a JSON object").into_response()),
+        };
+        // `$unset` lists dotted paths to remove after the merge
+        let separator = self.config.path_separator;
+        let unset = match unset.map(|paths| unset_paths(&paths, separator)) {
+            None => Vec::new(),
+            Some(Some(paths)) => paths,
+            Some(None) => {
+                return Ok(ApiError::bad_request(
+                    "$unset must be an array of non-empty dotted paths",
+                )
+                .into_response())
+            }
+        };
+        let guard = self.lock(&id).await;
+        let result = Arc::clone(&self)
+            .patch_document_locked(id.clone(), patch, unset)
+            .await;
+        drop(guard);
 
-    for part in parts {
-        let part = part.trim();
-        if part.is_empty() {
-            continue;
+        match result {
+            Ok(doc) => {
+                let status = StatusCode::OK;
+                let body = json!({ "status": status.as_str(), "id": id, "doc": doc });
+                Ok(reply::with_status(reply::json(&body), status).into_response())
+            }
+            Err(e) => Ok(e.into_response()),
         }
-        let value = current.get(part);
+    }
 
-        if value.is_none() {
-            return Value::Null;
+    async fn patch_document_locked(
+        self: Arc<Self>,
+        id: String,
+        patch: Value,
+        unset: Vec<Vec<String>>,
+    ) -> Result<Value, ApiError> {
+        let old = match self.get_live_document(id.clone())? {
+            Some(doc) => doc,
+            None => return Err(ApiError::not_found("document not found")),
+        };
+        let mut new = old.clone();
+        merge_patch(&mut new, &patch);
+        // missing paths are ignored
+        for parts in &unset {
+            remove_value_in_doc(&mut new, parts);
         }
-        current = value.unwrap();
+        apply_computed_fields(&mut new, &self.config.computed_fields);
+        stamp_document(&mut new, Some(&old));
+        self.replace_document(id, old, new.clone()).await?;
+        Ok(new)
     }
-    current.clone()
-}
 
-fn get_path_values(document: &Value, path: String) -> Vec<String> {
-    // each path_values is a string of the form "path:value"
-    let mut path_values = Vec::new();
-    let doc = document.as_object().unwrap();
-    for (key, value) in doc.into_iter() {
-        match value {
-            Value::Object(inner_map) => {
-                let new_path = format!("{}.{}", path, key);
-                path_values.extend(get_path_values(&json!(inner_map), new_path));
-            }
-            Value::Array(_) => {
-                // not supported
-                continue;
-            }
-            _ => {
-                let key = format!("{}.{}", path, key);
-                path_values.push(format!("{}:{}", key, value));
+    async fn update_document(
+        self: Arc<Self>,
+        id: String,
+        upsert: bool,
+        document: Value,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        if !document.is_object() {
+            return Ok(ApiError::bad_request("document must be a JSON object").into_response());
+        }
+        let guard = self.lock(&id).await;
+        let status = Arc::clone(&self)
+            .update_document_locked(id.clone(), upsert, document)
+            .await;
+        drop(guard);
+
+        match status? {
+            Some(status) => {
+                let body = json!({ "status": status.as_str(), "id": id });
+                Ok(reply::with_status(reply::json(&body), status).into_response())
             }
+            None => Ok(ApiError::not_found("document not found").into_response()),
         }
     }
 
-    path_values
-}
-
-#[derive(Debug)]
-struct QueryCondition {
-    key: String,
-    value: String,
-    op: String,
-}
-
-impl QueryCondition {
-    fn new(key: String, value: String, op: String) -> Self {
-        Self {
-            key: key,
-            value: value,
-            op: op,
+    // the status to answer with, or None if the document is missing
+    async fn update_document_locked(
+        self: Arc<Self>,
+        id: String,
+        upsert: bool,
+        mut document: Value,
+    ) -> Result<Option<StatusCode>, ServerError> {
+        apply_computed_fields(&mut document, &self.config.computed_fields);
+        // an upsert over a tombstone starts a new document
+        match self.get_live_document(id.clone())? {
+            Some(old) => {
+                stamp_document(&mut document, Some(&old));
+                self.replace_document(id, old, document).await?;
+                Ok(Some(StatusCode::OK))
+            }
+            None if upsert => {
+                stamp_document(&mut document, None);
+                let server_clone = Arc::clone(&self);
+                Arc::clone(&self)
+                    .index(&server_clone.index_db, id.clone(), document.clone())
+                    .await?;
+                let doc = serde_json::to_string(&document)?;
+                let mut batch = WriteBatch::default();
+                batch.put(&id, doc);
+                self.audit(&mut batch, &id, None, Some(&document));
+                let write_options = rocksdb::WriteOptions::default();
+                self.docs.write_opt(batch, &write_options)?;
+                self.invalidate_query_cache();
+                Ok(Some(StatusCode::CREATED))
+            }
+            None => Ok(None),
         }
     }
-}
 
-#[derive(Debug)]
+    // overwrite a stored document and move its index entries from `old` to `new`
+    async fn replace_document(
+        self: Arc<Self>,
+        id: String,
+        old: Value,
+        new: Value,
+    ) -> Result<(), ServerError> {
+        self.record_version(&id, &old)?;
+        let doc = serde_json::to_string(&new)?;
+        let mut batch = WriteBatch::default();
+        batch.put(&id, doc);
+        self.audit(&mut batch, &id, Some(&old), Some(&new));
+        let write_options = rocksdb::WriteOptions::default();
+        self.docs.write_opt(batch, &write_options)?;
+        self.invalidate_query_cache();
+        // reindex
+        let server_clone = Arc::clone(&self);
+        Arc::clone(&self)
+            .unindex(&server_clone.index_db, id.clone(), old)
+            .await?;
+        self.index(&server_clone.index_db, id, new).await
+    }
+
+    async fn get_document(
+        self: Arc<Self>,
+        id: String,
+        include_deleted: bool,
+        if_modified_since: Option<String>,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let start = Instant::now();
+        let response = self.get_document_response(id, include_deleted, if_modified_since);
+        Ok(with_timing(response, start))
+    }
+
+    fn get_document_response(
+        &self,
+        id: String,
+        include_deleted: bool,
+        if_modified_since: Option<String>,
+    ) -> reply::Response {
+        // read from db
+        let doc = if include_deleted {
+            self.get_document_by_id(id)
+        } else {
+            self.get_live_document(id)
+        };
+        let doc = match doc {
+            Ok(Some(doc)) => doc,
+            Ok(None) => return ApiError::not_found("document not found").into_response(),
+            Err(e) => return ApiError::from(e).into_response(),
+        };
+        let modified = doc
+            .get("_updated_at")
+            .or_else(|| doc.get("_created_at"))
+            .and_then(|v| v.as_str())
+            .and_then(parse_rfc3339);
+        // http dates have whole seconds, so the milliseconds of the document don't count.
+        // a missing or unparseable header, or a document without timestamps, is served.
+        let since = if_modified_since.as_deref().and_then(parse_http_date);
+        let last_modified = modified.map(|m| m.div_euclid(1000) * 1000);
+        if let (Some(modified), Some(since)) = (last_modified, since) {
+            if modified <= since {
+                let response = reply::with_status(warp::reply(), StatusCode::NOT_MODIFIED);
+                return with_last_modified(response.into_response(), last_modified);
+            }
+        }
+        // response
+        let status = StatusCode::OK;
+        let body = json!({ "status": status.as_str(), "doc": doc });
+        let response = reply::json(&body);
+        let response = reply::with_status(response, status).into_response();
+        with_last_modified(response, last_modified)
+    }
+
+    async fn get_document_field(
+        self: Arc<Self>,
+        id: String,
+        path: Option<String>,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(ApiError::bad_request("missing path parameter").into_response()),
+        };
+        let doc = match self.get_live_document(id.clone())? {
+            Some(doc) => doc,
+            None => return Ok(ApiError::not_found("document not found").into_response()),
+        };
+        let parts = split_path(&path, self.config.path_separator);
+        let value = get_value_from_doc(doc, &parts);
+        // the path doesn't resolve (or resolves to null)
+        if value.is_null() {
+            return Ok(reply::with_status(reply(), StatusCode::NO_CONTENT).into_response());
+        }
+        let status = StatusCode::OK;
+        let body = json!({ "status": status.as_str(), "id": id, "path": path, "value": value });
+        Ok(reply::with_status(reply::json(&body), status).into_response())
+    }
+
+    // GET /docs/:id/modified: only the timestamp of the last write, with a
+    // Last-Modified header
+    async fn document_modified(
+        self: Arc<Self>,
+        id: String,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let doc = match self.get_live_document(id.clone())? {
+            Some(doc) => doc,
+            None => return Ok(ApiError::not_found("document not found").into_response()),
+        };
+        // documents stored before the timestamps existed have neither
+        let updated_at = doc
+            .get("_updated_at")
+            .or_else(|| doc.get("_created_at"))
+            .and_then(|v| v.as_str())
+            .map(|v| v.to_string());
+        let status = StatusCode::OK;
+        let body = json!({ "status": status.as_str(), "id": id, "updated_at": updated_at });
+        let response = reply::with_status(reply::json(&body), status).into_response();
+        let modified = updated_at.as_deref().and_then(parse_rfc3339);
+        Ok(with_last_modified(response, modified))
+    }
+
+    // helper
+    fn get_document_by_id(&self, id: String) -> Result<Option<Value>, ServerError> {
+        let read_options = rocksdb::ReadOptions::default();
+        let doc = match self.docs.get_opt(id, &read_options)? {
+            Some(doc) => doc,
+            None => return Ok(None),
+        };
+        // make it to string
+        let doc = String::from_utf8(doc)?;
+        // convert to json
+        let doc: Value = serde_json::from_str(&doc)?;
+        Ok(Some(doc))
+    }
+
+    // the id stored for `hash_key`, if that document still has this content.
+    // updates and deletes don't touch the mapping, so it is checked here
+    fn find_duplicate(&self, hash_key: &str) -> Result<Option<String>, ServerError> {
+        let id = match self.index_db.get(hash_key)? {
+            Some(id) => String::from_utf8(id)?,
+            None => return Ok(None),
+        };
+        Ok(self
+            .get_live_document(id.clone())?
+            .filter(|doc| content_hash_key(doc) == hash_key)
+            .map(|_| id))
+    }
+
+    // like get_document_by_id, but a tombstone counts as missing
+    fn get_live_document(&self, id: String) -> Result<Option<Value>, ServerError> {
+        Ok(self
+            .get_document_by_id(id)?
+            .filter(|doc| !is_tombstone(doc)))
+    }
+
+    // (version, document) of the kept prior versions of `id`, oldest first
+    fn history(&self, id: &str) -> Result<Vec<(u64, Value)>, ServerError> {
+        let prefix = format!("{}\0", id);
+        let mode = IteratorMode::From(prefix.as_bytes(), Direction::Forward);
+        let mut versions = Vec::new();
+        for entry in self.index_db.iterator(mode) {
+            let (key, value) = entry?;
+            // ids can't contain `\0`, so the prefix only matches versions of `id`
+            let version = match key.strip_prefix(prefix.as_bytes()) {
+                Some(version) => String::from_utf8(version.to_vec())?,
+                None => break,
+            };
+            if let Ok(version) = version.parse::<u64>() {
+                versions.push((version, serde_json::from_slice(&value)?));
+            }
+        }
+        Ok(versions)
+    }
+
+    // keep `old` as the newest prior version of `id`, dropping the oldest
+    // versions beyond the history depth
+    fn record_version(&self, id: &str, old: &Value) -> Result<(), ServerError> {
+        let depth = self.config.history_depth;
+        if depth == 0 {
+            return Ok(());
+        }
+        let versions = self.history(id)?;
+        let next = versions.last().map_or(1, |(version, _)| version + 1);
+        let mut batch = WriteBatch::default();
+        batch.put(history_key(id, next), old.to_string());
+        let dropped = (versions.len() + 1).saturating_sub(depth);
+        for (version, _) in versions.iter().take(dropped) {
+            batch.delete(history_key(id, *version));
+        }
+        let write_options = rocksdb::WriteOptions::default();
+        self.index_db.write_opt(batch, &write_options)?;
+        Ok(())
+    }
+
+    // add the change of `id` from `old` to `new`, None when it doesn't exist, to
+    // the audit log. it goes in the batch making the change, so neither is
+    // written without the other
+    fn audit(&self, batch: &mut WriteBatch, id: &str, old: Option<&Value>, new: Option<&Value>) {
+        let cf = match self.docs.cf_handle(AUDIT_CF) {
+            Some(cf) if self.config.audit_log != AuditLog::Off => cf,
+            _ => return,
+        };
+        // a tombstone counts as missing, and removing one is purging it
+        fn live(doc: Option<&Value>) -> Option<&Value> {
+            doc.filter(|doc| !is_tombstone(doc))
+        }
+        let op = match (live(old), live(new)) {
+            (None, Some(_)) => "insert",
+            (Some(_), Some(_)) => "update",
+            (Some(_), None) => "delete",
+            (None, None) => "purge",
+        };
+        let millis = now_millis();
+        let mut entry = json!({ "at": format_rfc3339(millis), "id": id, "op": op });
+        if self.config.audit_log == AuditLog::Diff {
+            if let Some(new) = live(new) {
+                let empty = json!({});
+                entry["diff"] = diff_documents(live(old).unwrap_or(&empty), new);
+            }
+        }
+        let sequence = self.audit_sequence.fetch_add(1, AtomicOrdering::Relaxed);
+        batch.put_cf(cf, audit_key(millis, sequence), entry.to_string());
+    }
+
+    // GET /admin/audit: the audit log from `since` on, oldest first, `limit`
+    // entries at a time. `after` continues behind the `seq` of an entry
+    async fn audit_log(
+        self: Arc<Self>,
+        params: HashMap<String, String>,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let cf = match self.docs.cf_handle(AUDIT_CF) {
+            Some(cf) => cf,
+            None => return Ok(ApiError::not_found("the audit log is off").into_response()),
+        };
+        let since = match params.get("since").map(|v| parse_rfc3339(v)) {
+            None => 0,
+            Some(Some(since)) => since.max(0),
+            Some(None) => {
+                let error = ApiError::bad_request("since must be an RFC 3339 timestamp");
+                return Ok(error.into_response());
+            }
+        };
+        let limit = match params.get("limit").map(|v| v.parse::<usize>()) {
+            None => DEFAULT_AUDIT_LIMIT,
+            Some(Ok(limit)) if limit > 0 => limit,
+            Some(_) => {
+                let error = ApiError::bad_request("limit must be a positive integer");
+                return Ok(error.into_response());
+            }
+        };
+        let after = params.get("after");
+        let mut from = format!("{:016}", since);
+        if let Some(after) = after.filter(|after| **after > from) {
+            from = after.clone();
+        }
+
+        let mut entries = Vec::new();
+        let mut next = None;
+        let mode = IteratorMode::From(from.as_bytes(), Direction::Forward);
+        for entry in self.docs.iterator_cf(cf, mode) {
+            let (key, value) = entry.map_err(ServerError::from)?;
+            let seq = String::from_utf8(key.to_vec()).map_err(ServerError::from)?;
+            if after == Some(&seq) {
+                continue;
+            }
+            if entries.len() == limit {
+                next = entries.last().map(|entry: &Value| entry["seq"].clone());
+                break;
+            }
+            let mut entry: Value = serde_json::from_slice(&value).map_err(ServerError::from)?;
+            entry["seq"] = json!(seq);
+            entries.push(entry);
+        }
+        let status = StatusCode::OK;
+        let body = json!({
+            "status": status.as_str(),
+            "entries": entries,
+            "count": entries.len(),
+            "next": next,
+        });
+        Ok(reply::with_status(reply::json(&body), status).into_response())
+    }
+
+    async fn list_versions(
+        self: Arc<Self>,
+        id: String,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let versions = self
+            .history(&id)?
+            .into_iter()
+            .rev()
+            .map(|(version, doc)| json!({ "version": version, "doc": doc }))
+            .collect::<Vec<Value>>();
+        let status = StatusCode::OK;
+        let body = json!({ "status": status.as_str(), "id": id, "versions": versions });
+        Ok(reply::with_status(reply::json(&body), status))
+    }
+
+    async fn restore_document(
+        self: Arc<Self>,
+        id: String,
+        version: u64,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let guard = self.lock(&id).await;
+        let result = Arc::clone(&self)
+            .restore_document_locked(id.clone(), version)
+            .await;
+        drop(guard);
+
+        match result {
+            Ok(doc) => {
+                let status = StatusCode::OK;
+                let body =
+                    json!({ "status": status.as_str(), "id": id, "version": version, "doc": doc });
+                Ok(reply::with_status(reply::json(&body), status).into_response())
+            }
+            Err(e) => Ok(e.into_response()),
+        }
+    }
+
+    async fn restore_document_locked(
+        self: Arc<Self>,
+        id: String,
+        version: u64,
+    ) -> Result<Value, ApiError> {
+        let kept = match self.history(&id)?.into_iter().find(|(v, _)| *v == version) {
+            Some((_, doc)) => doc,
+            None => return Err(ApiError::not_found("version not found")),
+        };
+        let mut restored = kept.clone();
+        match self.get_live_document(id.clone())? {
+            Some(old) => {
+                stamp_document(&mut restored, Some(&old));
+                self.replace_document(id, old, restored.clone()).await?;
+            }
+            // the document was deleted since; bring it back under its old `_created_at`
+            None => {
+                stamp_document(&mut restored, Some(&kept));
+                let server_clone = Arc::clone(&self);
+                Arc::clone(&self)
+                    .index(&server_clone.index_db, id.clone(), restored.clone())
+                    .await?;
+                let doc = serde_json::to_string(&restored).map_err(ServerError::from)?;
+                let mut batch = WriteBatch::default();
+                batch.put(&id, doc);
+                self.audit(&mut batch, &id, None, Some(&restored));
+                let write_options = rocksdb::WriteOptions::default();
+                self.docs
+                    .write_opt(batch, &write_options)
+                    .map_err(ServerError::from)?;
+                self.invalidate_query_cache();
+            }
+        }
+        Ok(restored)
+    }
+
+    async fn search_documents(
+        self: Arc<Self>,
+        params: HashMap<String, String>,
+        queries: Vec<String>,
+        format: Option<ResultFormat>,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let start = Instant::now();
+        let format = match format {
+            Some(format) => format,
+            None => {
+                let error = ApiError::bad_request("format must be `json`, `ndjson` or `csv`");
+                return Ok(with_timing(error.into_response(), start));
+            }
+        };
+        // held until the response is built, or the stream has ended
+        let permit = match Arc::clone(&self.scan_permits).try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => return Ok(with_timing(too_many_scans(), start)),
+        };
+        // every repeated `q` must match, as if they were one query
+        let q = queries.join(" ");
+        let mut query = match parse_queries(&queries, self.config.max_value_length) {
+            Ok(q) => q,
+            Err(e) => {
+                let error = ApiError::bad_request(format!("Invalid query: {}", e));
+                return Ok(with_timing(error.into_response(), start));
+            }
+        };
+        query.set_epsilon(self.config.float_epsilon);
+        query.set_path_options(PathOptions {
+            case_insensitive: params.get("ci_keys").is_some_and(|v| v == "true"),
+            max_depth: self.config.max_path_depth,
+            separator: self.config.path_separator,
+        });
+        query.include_deleted = params.get("include_deleted").is_some_and(|v| v == "true");
+        if let Some(since) = params.get("modified_since") {
+            match parse_rfc3339(since) {
+                Some(since) => query.modified_since = Some(since),
+                None => {
+                    let error =
+                        ApiError::bad_request("modified_since must be an RFC 3339 timestamp");
+                    return Ok(with_timing(error.into_response(), start));
+                }
+            }
+        }
+        for (param, bound) in [
+            ("created_from", &mut query.created_from),
+            ("created_to", &mut query.created_to),
+        ] {
+            if let Some(value) = params.get(param) {
+                match parse_rfc3339(value) {
+                    Some(value) => *bound = Some(value),
+                    None => {
+                        let message = format!("{} must be an RFC 3339 timestamp", param);
+                        let error = ApiError::bad_request(message);
+                        return Ok(with_timing(error.into_response(), start));
+                    }
+                }
+            }
+        }
+        if let (Some(from), Some(to)) = (query.created_from, query.created_to) {
+            if from > to {
+                let error = ApiError::bad_request("created_from must not be after created_to");
+                return Ok(with_timing(error.into_response(), start));
+            }
+        }
+        let page = match Page::from_params(&params) {
+            Ok(page) => page,
+            Err(e) => return Ok(with_timing(e.into_response(), start)),
+        };
+        let sort = Sort::from_params(&params, self.config.path_separator);
+        // an empty `?fields=` asks for whole documents despite DEFAULT_FIELDS
+        let fields = match params.get("fields") {
+            Some(fields) => parse_fields(fields),
+            None => self.config.default_fields.clone(),
+        };
+        let ids_only = params.get("ids_only").is_some_and(|v| v == "true");
+        let group_by = params
+            .get("group_by")
+            .filter(|field| !field.is_empty())
+            .map(|field| split_path(field, self.config.path_separator));
+        let as_map = match params.get("shape").map(|v| v.as_str()) {
+            None | Some("array") => false,
+            Some("map") => true,
+            Some(_) => {
+                let error = ApiError::bad_request("shape must be array or map");
+                return Ok(with_timing(error.into_response(), start));
+            }
+        };
+        let group_count = params.get("group_count").is_some_and(|v| v == "true");
+        if group_count && group_by.is_none() {
+            let error = ApiError::bad_request("group_count needs group_by");
+            return Ok(with_timing(error.into_response(), start));
+        }
+        let distinct_by = params
+            .get("distinct_by")
+            .filter(|field| !field.is_empty())
+            .map(|field| split_path(field, self.config.path_separator));
+        let projection = Projection {
+            fields,
+            ids_only,
+            group_by,
+            as_map,
+            group_count,
+            distinct_by,
+        };
+        // the values kept on earlier pages aren't known behind a cursor
+        if projection.distinct_by.is_some() && page.after.is_some() {
+            let error = ApiError::bad_request("distinct_by cannot be combined with after");
+            return Ok(with_timing(error.into_response(), start));
+        }
+        if projection.group_by.is_some() && format != ResultFormat::Json {
+            let error = ApiError::bad_request("group_by is only supported for JSON results");
+            return Ok(with_timing(error.into_response(), start));
+        }
+        if projection.as_map && (format != ResultFormat::Json || ids_only) {
+            let error =
+                ApiError::bad_request("shape=map is only supported for JSON results with bodies");
+            return Ok(with_timing(error.into_response(), start));
+        }
+        if projection.as_map && projection.group_by.is_some() {
+            let error = ApiError::bad_request("shape=map cannot be combined with group_by");
+            return Ok(with_timing(error.into_response(), start));
+        }
+        // a cursor is a position in key order, which a sort would change
+        if page.after.is_some() && (sort.is_some() || query.modified_since.is_some()) {
+            let error =
+                ApiError::bad_request("after cannot be combined with sort or modified_since");
+            return Ok(with_timing(error.into_response(), start));
+        }
+        let stream = params.get("stream").is_some_and(|v| v == "true");
+        // a stream always iterates the whole db
+        if self.config.reject_full_scans && (stream || query.index_lookups().is_empty()) {
+            return Ok(with_timing(full_scan_rejected().into_response(), start));
+        }
+        if stream {
+            if sort.is_some() {
+                let error = ApiError::bad_request("sort cannot be combined with stream");
+                return Ok(with_timing(error.into_response(), start));
+            }
+            if projection.group_by.is_some() {
+                let error = ApiError::bad_request("group_by cannot be combined with stream");
+                return Ok(with_timing(error.into_response(), start));
+            }
+            if projection.as_map {
+                let error = ApiError::bad_request("shape=map cannot be combined with stream");
+                return Ok(with_timing(error.into_response(), start));
+            }
+            if projection.distinct_by.is_some() {
+                let error = ApiError::bad_request("distinct_by cannot be combined with stream");
+                return Ok(with_timing(error.into_response(), start));
+            }
+            return Ok(self.search_stream(query, page, projection, permit));
+        }
+        // delta pulls come oldest first, so a client can checkpoint at the last one
+        let sort = match sort {
+            None if query.modified_since.is_some() => Some(Sort::by_updated_at()),
+            // an empty `?sort=` keeps key order, and a cursor needs it
+            None if !params.contains_key("sort") && page.after.is_none() => {
+                self.config.default_sort.clone()
+            }
+            sort => sort,
+        };
+        let mut stats = ScanStats::default();
+        let response = self
+            .search_response(query, format, page, sort, projection, &mut stats)
+            .unwrap_or_else(|e| ApiError::from(e).into_response());
+        drop(permit);
+        self.log_if_slow(&q, &stats, start);
+        Ok(with_timing(response, start))
+    }
+
+    // POST /docs/search: like GET /docs, but with `ids` only those documents are
+    // checked against the query instead of scanning the whole db
+    async fn search_documents_by_body(
+        self: Arc<Self>,
+        request: SearchRequest,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let start = Instant::now();
+        let permit = match Arc::clone(&self.scan_permits).try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => return Ok(with_timing(too_many_scans(), start)),
+        };
+        let mut query = match parse_query(&request.q, self.config.max_value_length) {
+            Ok(q) => q,
+            Err(e) => {
+                let error = ApiError::bad_request(format!("Invalid query: {}", e));
+                return Ok(with_timing(error.into_response(), start));
+            }
+        };
+        query.set_epsilon(self.config.float_epsilon);
+        query.set_path_options(PathOptions {
+            case_insensitive: request.ci_keys,
+            max_depth: self.config.max_path_depth,
+            separator: self.config.path_separator,
+        });
+        query.include_deleted = request.include_deleted;
+        if self.config.reject_full_scans
+            && request.ids.is_none()
+            && query.index_lookups().is_empty()
+        {
+            return Ok(with_timing(full_scan_rejected().into_response(), start));
+        }
+        let page = Page {
+            limit: request.limit,
+            offset: request.offset,
+            after: None,
+        };
+        let mut stats = ScanStats::default();
+        let response = match request.ids {
+            Some(ids) => self.search_ids(&query, ids, page, &mut stats),
+            None => self.search_response(
+                query,
+                ResultFormat::Json,
+                page,
+                None,
+                Projection::default(),
+                &mut stats,
+            ),
+        }
+        .unwrap_or_else(|e| ApiError::from(e).into_response());
+        drop(permit);
+        self.log_if_slow(&request.q, &stats, start);
+        Ok(with_timing(response, start))
+    }
+
+    // POST /docs/mget: the documents of `ids` in request order, null for the
+    // missing ones, or with `strict` a 404 listing them
+    async fn get_documents(
+        self: Arc<Self>,
+        request: MgetRequest,
+        strict: bool,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut seen = HashSet::new();
+        let ids = request
+            .ids
+            .into_iter()
+            .filter(|id| seen.insert(id.clone()))
+            .collect::<Vec<String>>();
+        let mut documents = Vec::new();
+        let mut missing = Vec::new();
+        for (id, doc) in ids.iter().zip(self.docs.multi_get(&ids)) {
+            let doc = doc
+                .map_err(ServerError::from)?
+                .and_then(|doc| decode_record(id.as_bytes(), &doc))
+                .map(|(_, doc)| doc)
+                .filter(|doc| !is_tombstone(doc));
+            if doc.is_none() {
+                missing.push(id.clone());
+            }
+            documents.push(json!({ "id": id, "body": doc }));
+        }
+        if strict && !missing.is_empty() {
+            let status = StatusCode::NOT_FOUND;
+            let body = json!({
+                "error": {
+                    "message": "documents not found",
+                    "code": status.as_u16(),
+                    "missing": missing,
+                }
+            });
+            return Ok(reply::with_status(reply::json(&body), status).into_response());
+        }
+        let status = StatusCode::OK;
+        let body = json!({
+            "status": status.as_str(),
+            "documents": documents,
+            "count": documents.len(),
+            "missing": missing,
+        });
+        Ok(reply::with_status(reply::json(&body), status).into_response())
+    }
+
+    fn log_if_slow(&self, q: &str, stats: &ScanStats, start: Instant) {
+        let elapsed = start.elapsed();
+        if self
+            .config
+            .slow_query
+            .is_some_and(|threshold| elapsed > threshold)
+        {
+            tracing::warn!(
+                query = %q,
+                matched = stats.matched,
+                scanned = stats.scanned,
+                duration_ms = elapsed.as_millis() as u64,
+                "slow query"
+            );
+        }
+    }
+
+    // the documents of `ids` that match `query`, in the order of `ids`
+    fn search_ids(
+        &self,
+        query: &Query,
+        ids: Vec<String>,
+        page: Page,
+        stats: &mut ScanStats,
+    ) -> Result<reply::Response, ServerError> {
+        let mut seen = HashSet::new();
+        let ids = ids
+            .into_iter()
+            .filter(|id| seen.insert(id.clone()))
+            .collect::<Vec<String>>();
+        let mut documents = Vec::new();
+        for (id, doc) in ids.iter().zip(self.docs.multi_get(&ids)) {
+            let doc = match doc? {
+                Some(doc) => match decode_record(id.as_bytes(), &doc) {
+                    Some((_, doc)) => doc,
+                    None => continue,
+                },
+                None => continue,
+            };
+            stats.scanned += 1;
+            if query.matches(&doc) {
+                documents.push(json!({
+                    "id": id,
+                    "body": doc,
+                }));
+            }
+        }
+        stats.matched = documents.len();
+        let documents = documents
+            .into_iter()
+            .skip(page.offset)
+            .take(page.limit.unwrap_or(usize::MAX))
+            .collect::<Vec<Value>>();
+        let response = json!({
+            "documents": documents,
+            "count": documents.len(),
+            "scanned": stats.scanned,
+            "matched": stats.matched,
+            "offset": page.offset,
+            "limit": page.limit,
+        });
+        Ok(reply::with_status(reply::json(&response), StatusCode::OK).into_response())
+    }
+
+    // stream matches as ndjson while iterating, instead of collecting them first
+    fn search_stream(
+        self: Arc<Self>,
+        query: Query,
+        page: Page,
+        projection: Projection,
+        permit: tokio::sync::OwnedSemaphorePermit,
+    ) -> reply::Response {
+        let (mut sender, body) = warp::hyper::Body::channel();
+        let handle = tokio::runtime::Handle::current();
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let limit = page.limit.unwrap_or(usize::MAX);
+            let mut skipped = 0;
+            let mut sent = 0;
+            let mut bytes = 0;
+            let mode = match &page.after {
+                Some(after) => IteratorMode::From(after.as_bytes(), Direction::Forward),
+                None => IteratorMode::Start,
+            };
+            for entry in self.docs.iterator(mode) {
+                if sent == limit {
+                    break;
+                }
+                // the status is already sent, so errors can only cut the body short
+                let (key, value) = match entry {
+                    Ok(entry) => entry,
+                    Err(_) => return sender.abort(),
+                };
+                if page.after.as_deref().map(str::as_bytes) == Some(&*key) {
+                    continue;
+                }
+                let (id, document) = match decode_record(&key, &value) {
+                    Some(record) => record,
+                    None => continue,
+                };
+                if !query.matches(&document) {
+                    continue;
+                }
+                if skipped < page.offset {
+                    skipped += 1;
+                    continue;
+                }
+                let mut line = match &projection.fields {
+                    _ if projection.ids_only => json!({ "id": id }),
+                    Some(fields) => {
+                        let body = project_fields(&document, fields, self.config.path_separator);
+                        json!({ "id": id, "body": body })
+                    }
+                    None => json!({ "id": id, "body": document }),
+                }
+                .to_string();
+                line.push('\n');
+                // the headers are out, so a stream over the size cap just ends
+                bytes += line.len();
+                if sent > 0 && bytes > self.config.max_response_bytes {
+                    break;
+                }
+                // waits while the client is behind, fails once it has gone away
+                if handle.block_on(sender.send_data(line.into())).is_err() {
+                    return;
+                }
+                sent += 1;
+            }
+        });
+        let mut response = reply::Response::new(body);
+        response.headers_mut().insert(
+            "content-type",
+            HeaderValue::from_static("application/x-ndjson"),
+        );
+        response
+    }
+
+    fn search_response(
+        &self,
+        query: Query,
+        format: ResultFormat,
+        page: Page,
+        sort: Option<Sort>,
+        projection: Projection,
+        stats: &mut ScanStats,
+    ) -> Result<reply::Response, ServerError> {
+        // a query without conditions lists every document, so it is always paged
+        let limit = match page.limit {
+            Some(limit) => Some(limit),
+            None if query.conditions.is_empty() => Some(self.config.default_list_limit),
+            None => None,
+        };
+
+        // without a sort, the page is complete after offset + limit matches,
+        // and one more tells whether there is a next page. collapsing needs
+        // every match too, since a page may shrink
+        let max = match sort {
+            None if projection.distinct_by.is_none() => {
+                limit.map(|limit| page.offset.saturating_add(limit).saturating_add(1))
+            }
+            _ => None,
+        };
+        // facet counts cover every match, so neither paging nor sorting applies
+        if let (true, Some(path)) = (projection.group_count, &projection.group_by) {
+            let mut documents =
+                self.cached_documents(&query, None, None, page.after.as_deref(), stats)?;
+            if let Some(path) = &projection.distinct_by {
+                keep_first_per_value(&mut documents, path);
+            }
+            let mut groups: BTreeMap<String, usize> = BTreeMap::new();
+            for document in &documents {
+                *groups
+                    .entry(group_name(&document["body"], path))
+                    .or_insert(0) += 1;
+            }
+            let response = json!({
+                "groups": groups,
+                "count": documents.len(),
+                "scanned": stats.scanned,
+                "matched": stats.matched,
+            });
+            return Ok(reply::with_status(reply::json(&response), StatusCode::OK).into_response());
+        }
+        // a cursor resumes in key order, so a sorted page has none. a `sort=`,
+        // a `modified_since` pull and `distinct_by` would reject it anyway, and
+        // the page behind it would skip DEFAULT_SORT
+        let resumable = sort.is_none() && projection.distinct_by.is_none();
+        // whole bodies in key order are measured like the trimming below does,
+        // so the scan can stop at MAX_RESPONSE_BYTES already
+        let mut budget = (resumable && projection.fields.is_none() && !projection.ids_only)
+            .then(|| ByteBudget::new(page.offset, self.config.max_response_bytes));
+        let mut documents =
+            self.cached_documents(&query, max, budget.as_mut(), page.after.as_deref(), stats)?;
+
+        if let Some(sort) = sort {
+            sort.apply(&mut documents);
+        }
+        if let Some(path) = &projection.distinct_by {
+            keep_first_per_value(&mut documents, path);
+        }
+
+        let has_more =
+            limit.is_some_and(|limit| documents.len() > page.offset.saturating_add(limit));
+        let mut documents = documents
+            .into_iter()
+            .skip(page.offset)
+            .take(limit.unwrap_or(usize::MAX))
+            .collect::<Vec<Value>>();
+        // groups are taken from the whole body, before `fields` projects it
+        let group_names = projection.group_by.map(|path| {
+            documents
+                .iter()
+                .map(|document| group_name(&document["body"], &path))
+                .collect::<Vec<String>>()
+        });
+        if let (false, Some(fields)) = (projection.ids_only, &projection.fields) {
+            for document in documents.iter_mut() {
+                document["body"] =
+                    project_fields(&document["body"], fields, self.config.path_separator);
+            }
+        }
+
+        // the page ends early once MAX_RESPONSE_BYTES worth of entries are in
+        // it, though never before the first one
+        let mut size = 0;
+        let kept = documents
+            .iter()
+            .enumerate()
+            .take_while(|(i, document)| {
+                let entry = if projection.ids_only {
+                    &document["id"]
+                } else {
+                    *document
+                };
+                size += entry.to_string().len() + 1;
+                *i == 0 || size <= self.config.max_response_bytes
+            })
+            .count();
+        let truncated = kept < documents.len();
+        documents.truncate(kept);
+        let has_more = has_more || truncated;
+        // the last id of this page; `?after=` continues behind it
+        let next_cursor = documents
+            .last()
+            .and_then(|document| document["id"].as_str())
+            .filter(|_| has_more && resumable)
+            .map(encode_cursor);
+
+        // no bodies at all, in any format
+        if projection.ids_only {
+            let ids = documents
+                .iter()
+                .map(|document| document["id"].clone())
+                .collect::<Vec<Value>>();
+            let count = ids.len();
+            if let Some(names) = group_names {
+                let response = json!({
+                    "groups": group_values(ids, names),
+                    "count": count,
+                    "scanned": stats.scanned,
+                    "matched": stats.matched,
+                    "offset": page.offset,
+                    "limit": limit,
+                    "has_more": has_more,
+                    "next_cursor": next_cursor,
+                    "truncated": truncated,
+                });
+                return Ok(
+                    reply::with_status(reply::json(&response), StatusCode::OK).into_response()
+                );
+            }
+            let response = json!({
+                "ids": ids,
+                "count": ids.len(),
+                "scanned": stats.scanned,
+                "matched": stats.matched,
+                "offset": page.offset,
+                "limit": limit,
+                "has_more": has_more,
+                "next_cursor": next_cursor,
+                "truncated": truncated,
+            });
+            return Ok(reply::with_status(reply::json(&response), StatusCode::OK).into_response());
+        }
+
+        match format {
+            ResultFormat::Csv => {
+                let csv = documents_to_csv(&documents);
+                let response = warp::reply::with_status(csv, StatusCode::OK);
+                let response =
+                    warp::reply::with_header(response, "content-type", "text/csv; charset=utf-8");
+                return Ok(with_truncated(response.into_response(), truncated));
+            }
+            ResultFormat::Ndjson => {
+                let mut lines = String::new();
+                for document in documents.iter() {
+                    lines.push_str(&document.to_string());
+                    lines.push('\n');
+                }
+                let response = warp::reply::with_status(lines, StatusCode::OK);
+                let response =
+                    warp::reply::with_header(response, "content-type", "application/x-ndjson");
+                return Ok(with_truncated(response.into_response(), truncated));
+            }
+            ResultFormat::Json => {}
+        }
+
+        let count = documents.len();
+        if let Some(names) = group_names {
+            let response = json!({
+                "groups": group_values(documents, names),
+                "count": count,
+                "scanned": stats.scanned,
+                "matched": stats.matched,
+                "offset": page.offset,
+                "limit": limit,
+                "has_more": has_more,
+                "next_cursor": next_cursor,
+                "truncated": truncated,
+            });
+            return Ok(reply::with_status(reply::json(&response), StatusCode::OK).into_response());
+        }
+        // `?shape=map`: `{"<id>": <body>}` instead of `[{"id", "body"}]`
+        let documents = if projection.as_map {
+            let map = documents
+                .into_iter()
+                .filter_map(|mut document| {
+                    let id = document["id"].as_str()?.to_string();
+                    Some((id, document["body"].take()))
+                })
+                .collect::<serde_json::Map<String, Value>>();
+            Value::Object(map)
+        } else {
+            Value::Array(documents)
+        };
+        let response = json!({
+            "documents": documents,
+            "count": count,
+            "scanned": stats.scanned,
+            "matched": stats.matched,
+            "offset": page.offset,
+            "limit": limit,
+            "has_more": has_more,
+            "next_cursor": next_cursor,
+            "truncated": truncated,
+        });
+        Ok(reply::with_status(reply::json(&response), StatusCode::OK).into_response())
+    }
+
+    // `{"id", "body"}` of the documents matching `query`. the scan stops after
+    // `max` matches, or once they are over `budget`, so a page doesn't need
+    // every match in memory.
+    fn find_documents(
+        &self,
+        query: &Query,
+        max: Option<usize>,
+        mut budget: Option<&mut ByteBudget>,
+        after: Option<&str>,
+        stats: &mut ScanStats,
+    ) -> Result<Vec<Value>, ServerError> {
+        let max = max.unwrap_or(usize::MAX);
+        let mut documents = Vec::new();
+
+        // a document has to be in one posting list of every lookup
+        let lookups = query.index_lookups();
+        if !lookups.is_empty() {
+            let mut candidates: Option<HashSet<String>> = None;
+            for keys in lookups {
+                let mut ids = HashSet::new();
+                for key in keys {
+                    if let Some(list) = self.index_db.get(key)? {
+                        let list = String::from_utf8(list)?;
+                        ids.extend(list.split(',').map(|id| id.to_string()));
+                    }
+                }
+                candidates = Some(match candidates {
+                    Some(candidates) => candidates.intersection(&ids).cloned().collect(),
+                    None => ids,
+                });
+            }
+            // in key order, like a full scan
+            let ids = candidates
+                .unwrap_or_default()
+                .into_iter()
+                .collect::<BTreeSet<String>>();
+            let after = match after {
+                Some(after) => Bound::Excluded(after),
+                None => Bound::Unbounded,
+            };
+            for id in ids.range::<str, _>((after, Bound::Unbounded)) {
+                if documents.len() == max {
+                    break;
+                }
+                let doc = match self.docs.get(id)? {
+                    Some(value) => match decode_record(id.as_bytes(), &value) {
+                        Some((_, doc)) => doc,
+                        None => continue,
+                    },
+                    None => continue,
+                };
+                stats.scanned += 1;
+                // the other conditions aren't answered by the index
+                if query.matches(&doc) {
+                    documents.push(json!({
+                        "id": id,
+                        "body": doc,
+                    }));
+                    if budget.as_deref_mut().is_some_and(|b| b.spend(&documents)) {
+                        break;
+                    }
+                }
+            }
+        } else {
+            let mode = match after {
+                Some(after) => IteratorMode::From(after.as_bytes(), Direction::Forward),
+                None => IteratorMode::Start,
+            };
+            for entry in self.docs.iterator(mode) {
+                if documents.len() == max {
+                    break;
+                }
+                let (key, value) = entry?;
+                if after.map(str::as_bytes) == Some(&*key) {
+                    continue;
+                }
+                let (id, document) = match decode_record(&key, &value) {
+                    Some(record) => record,
+                    None => continue,
+                };
+                stats.scanned += 1;
+                if query.matches(&document) {
+                    documents.push(json!({
+                        "id": id,
+                        "body": document,
+                    }));
+                    if budget.as_deref_mut().is_some_and(|b| b.spend(&documents)) {
+                        break;
+                    }
+                }
+            }
+        }
+        stats.matched = documents.len();
+        Ok(documents)
+    }
+
+    // `find_documents` through the query cache, when it is enabled. the cache
+    // keeps ids only, so current bodies are returned even on a hit.
+    fn cached_documents(
+        &self,
+        query: &Query,
+        max: Option<usize>,
+        mut budget: Option<&mut ByteBudget>,
+        after: Option<&str>,
+        stats: &mut ScanStats,
+    ) -> Result<Vec<Value>, ServerError> {
+        let ttl = match self.config.query_cache_ttl {
+            // an empty query lists everything, which isn't worth keeping, and
+            // pages behind a cursor are only ever part of the matches
+            Some(ttl) if !query.conditions.is_empty() && after.is_none() => ttl,
+            _ => return self.find_documents(query, max, budget, after, stats),
+        };
+        let key = query.normalized();
+        let cached = {
+            let cache = self.query_cache.lock().unwrap();
+            cache
+                .get(&key)
+                .filter(|(at, _)| at.elapsed() < ttl)
+                .map(|(_, ids)| ids.clone())
+        };
+        if let Some(ids) = cached {
+            let mut documents = Vec::new();
+            for id in ids.into_iter().take(max.unwrap_or(usize::MAX)) {
+                // skipped like in `find_documents` when it doesn't decode
+                let record = match self.docs.get(&id)? {
+                    Some(value) => decode_record(id.as_bytes(), &value),
+                    None => None,
+                };
+                if let Some((id, doc)) = record {
+                    documents.push(json!({ "id": id, "body": doc }));
+                    if budget.as_deref_mut().is_some_and(|b| b.spend(&documents)) {
+                        break;
+                    }
+                }
+            }
+            // the ids came from the cache, no document was scanned for them
+            stats.scanned = 0;
+            stats.matched = documents.len();
+            return Ok(documents);
+        }
+
+        let documents = self.find_documents(query, max, budget.as_deref_mut(), None, stats)?;
+        // a scan that stopped early doesn't know all matches
+        if max.is_some_and(|max| documents.len() >= max) || budget.is_some_and(|b| b.is_spent()) {
+            return Ok(documents);
+        }
+        let ids = documents
+            .iter()
+            .filter_map(|doc| doc["id"].as_str().map(|id| id.to_string()))
+            .collect();
+        let mut cache = self.query_cache.lock().unwrap();
+        if cache.len() >= QUERY_CACHE_CAPACITY {
+            cache.retain(|_, (at, _)| at.elapsed() < ttl);
+            if cache.len() >= QUERY_CACHE_CAPACITY {
+                cache.clear();
+            }
+        }
+        cache.insert(key, (Instant::now(), ids));
+        Ok(documents)
+    }
+
+    // any write may change which documents match a cached query
+    fn invalidate_query_cache(&self) {
+        if self.config.query_cache_ttl.is_some() {
+            self.query_cache.lock().unwrap().clear();
+        }
+    }
+
+    async fn distinct_values(
+        self: Arc<Self>,
+        field: Option<String>,
+        with_counts: bool,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let field = match field {
+            Some(field) if !field.is_empty() => field,
+            _ => return Ok(ApiError::bad_request("missing `field` parameter").into_response()),
+        };
+        let parts = split_path(&field, self.config.path_separator);
+        let _permit = match self.scan_permits.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => return Ok(too_many_scans()),
+        };
+
+        // keyed by the serialized value, so equal values are counted once
+        let mut counts: HashMap<String, (Value, usize)> = HashMap::new();
+        for entry in self.docs.iterator(IteratorMode::Start) {
+            let (key, value) = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    return Ok(
+                        ApiError::internal(format!("Database error: {:?}", e)).into_response()
+                    )
+                }
+            };
+            let document = match decode_record(&key, &value) {
+                Some((_, doc)) => doc,
+                None => continue,
+            };
+            if is_tombstone(&document) {
+                continue;
+            }
+            let value = get_value_from_doc(document, &parts);
+            // documents without the field don't contribute a value
+            if value.is_null() {
+                continue;
+            }
+            let key = value.to_string();
+            counts.entry(key).or_insert((value, 0)).1 += 1;
+        }
+
+        let mut values = counts.into_values().collect::<Vec<(Value, usize)>>();
+        values.sort_by(|(a, _), (b, _)| compare_values(a, b));
+        let values = values
+            .into_iter()
+            .map(|(value, count)| {
+                if with_counts {
+                    json!({ "value": value, "count": count })
+                } else {
+                    value
+                }
+            })
+            .collect::<Vec<Value>>();
+
+        let status = StatusCode::OK;
+        let body = json!({
+            "status": status.as_str(),
+            "field": field,
+            "count": values.len(),
+            "values": values,
+        });
+        Ok(reply::with_status(reply::json(&body), status).into_response())
+    }
+
+    // GET /docs/size: the stored bytes of the documents matching `q`. always a
+    // full scan, since the posting lists don't know the sizes
+    async fn documents_size(
+        self: Arc<Self>,
+        params: HashMap<String, String>,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let q = params.get("q").map(|q| q.as_str()).unwrap_or("");
+        let mut query = match parse_query(q, self.config.max_value_length) {
+            Ok(q) => q,
+            Err(e) => {
+                let error = ApiError::bad_request(format!("Invalid query: {}", e));
+                return Ok(error.into_response());
+            }
+        };
+        query.set_epsilon(self.config.float_epsilon);
+        query.set_path_options(PathOptions {
+            case_insensitive: params.get("ci_keys").is_some_and(|v| v == "true"),
+            max_depth: self.config.max_path_depth,
+            separator: self.config.path_separator,
+        });
+        query.include_deleted = params.get("include_deleted").is_some_and(|v| v == "true");
+        if self.config.reject_full_scans {
+            return Ok(full_scan_rejected().into_response());
+        }
+        let _permit = match self.scan_permits.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => return Ok(too_many_scans()),
+        };
+
+        let mut stats = ScanStats::default();
+        let mut total_bytes = 0u64;
+        for entry in self.docs.iterator(IteratorMode::Start) {
+            let (key, value) = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    return Ok(
+                        ApiError::internal(format!("Database error: {:?}", e)).into_response()
+                    )
+                }
+            };
+            stats.scanned += 1;
+            let document = match decode_record(&key, &value) {
+                Some((_, doc)) => doc,
+                None => continue,
+            };
+            if !query.matches(&document) {
+                continue;
+            }
+            stats.matched += 1;
+            // the value as stored, without the key or rocksdb's own overhead
+            total_bytes += value.len() as u64;
+        }
+
+        let status = StatusCode::OK;
+        let average_bytes = match stats.matched {
+            0 => None,
+            matched => Some(total_bytes / matched as u64),
+        };
+        let body = json!({
+            "status": status.as_str(),
+            "count": stats.matched,
+            "scanned": stats.scanned,
+            "total_bytes": total_bytes,
+            "average_bytes": average_bytes,
+        });
+        Ok(reply::with_status(reply::json(&body), status).into_response())
+    }
+
+    // GET /docs/explain: how a search for `q` would run, without reading any
+    // document. the estimate comes from posting list sizes, or from rocksdb's
+    // key count for a full scan
+    async fn explain(
+        self: Arc<Self>,
+        params: HashMap<String, String>,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let q = params.get("q").map(|q| q.as_str()).unwrap_or("");
+        let mut query = match parse_query(q, self.config.max_value_length) {
+            Ok(q) => q,
+            Err(e) => {
+                let error = ApiError::bad_request(format!("Invalid query: {}", e));
+                return Ok(error.into_response());
+            }
+        };
+        query.set_path_options(PathOptions {
+            case_insensitive: params.get("ci_keys").is_some_and(|v| v == "true"),
+            max_depth: self.config.max_path_depth,
+            separator: self.config.path_separator,
+        });
+        query.include_deleted = params.get("include_deleted").is_some_and(|v| v == "true");
+
+        let lookups = query.index_lookups();
+        let indexable = !query.path_options.case_insensitive && !query.include_deleted;
+        let conditions = query
+            .conditions
+            .iter()
+            .map(|c| {
+                let value = match &c.rhs_field {
+                    Some(path) => format!("field:{}", path),
+                    None => c.value.clone(),
+                };
+                json!({
+                    "key": c.key,
+                    "op": c.op,
+                    "value": value,
+                    "negate": c.negate,
+                    "index": indexable && c.index_keys(query.path_options).is_some(),
+                })
+            })
+            .collect::<Vec<Value>>();
+        let full_scan = lookups.is_empty();
+        let estimated_documents = if full_scan {
+            self.docs
+                .property_int_value("rocksdb.estimate-num-keys")
+                .map_err(ServerError::from)?
+                .unwrap_or(0)
+        } else {
+            // the smallest union of posting lists bounds the candidates
+            let mut estimate = u64::MAX;
+            for keys in &lookups {
+                let mut ids = 0;
+                for key in keys {
+                    if let Some(list) = self.index_db.get(key).map_err(ServerError::from)? {
+                        ids += list.split(|b| *b == b',').count() as u64;
+                    }
+                }
+                estimate = estimate.min(ids);
+            }
+            estimate
+        };
+
+        let status = StatusCode::OK;
+        let body = json!({
+            "conditions": conditions,
+            "index_lookups": lookups,
+            "full_scan": full_scan,
+            "rejected": full_scan && self.config.reject_full_scans,
+            "estimated_documents": estimated_documents,
+        });
+        Ok(reply::with_status(reply::json(&body), status).into_response())
+    }
+
+    // GET /docs/schema: the dotted paths found in the first `sample` documents,
+    // each with the number of times every json type was seen there
+    async fn schema(
+        self: Arc<Self>,
+        sample: Option<String>,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let sample = match sample.map(|v| v.parse::<usize>()) {
+            None => DEFAULT_SCHEMA_SAMPLE,
+            Some(Ok(sample)) if sample > 0 => sample,
+            Some(_) => {
+                let error = ApiError::bad_request("sample must be a positive integer");
+                return Ok(error.into_response());
+            }
+        };
+        let _permit = match self.scan_permits.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => return Ok(too_many_scans()),
+        };
+
+        let mut fields = BTreeMap::new();
+        let mut sampled = 0;
+        for entry in self.docs.iterator(IteratorMode::Start).take(sample) {
+            let (key, value) = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    return Ok(
+                        ApiError::internal(format!("Database error: {:?}", e)).into_response()
+                    )
+                }
+            };
+            let document = match decode_record(&key, &value) {
+                Some((_, doc)) => doc,
+                None => continue,
+            };
+            if is_tombstone(&document) {
+                continue;
+            }
+            if let Value::Object(map) = &document {
+                let (depth, separator) = (self.config.max_path_depth, self.config.path_separator);
+                collect_field_types(map, "", depth, separator, &mut fields);
+            }
+            sampled += 1;
+        }
+
+        let status = StatusCode::OK;
+        let body = json!({
+            "status": status.as_str(),
+            "sampled": sampled,
+            "fields": fields,
+        });
+        Ok(reply::with_status(reply::json(&body), status).into_response())
+    }
+
+    async fn compact(self: Arc<Self>) -> Result<impl warp::Reply, warp::Rejection> {
+        let before = self.size_estimates();
+        let start = Instant::now();
+        self.maintenance.store(true, AtomicOrdering::SeqCst);
+        // a full compaction blocks for a long time, keep it off the async workers
+        let server_clone = Arc::clone(&self);
+        let bulk_load = self.bulk_load.load(AtomicOrdering::SeqCst);
+        let result = tokio::task::spawn_blocking(move || {
+            server_clone.docs.compact_range::<&[u8], &[u8]>(None, None);
+            // the end of a bulk load: catch up on the index too, then let
+            // rocksdb schedule compactions again
+            if bulk_load {
+                server_clone
+                    .index_db
+                    .compact_range::<&[u8], &[u8]>(None, None);
+                for db in [&server_clone.docs, &server_clone.index_db] {
+                    db.set_options(&[("disable_auto_compactions", "false")])?;
+                }
+                server_clone.bulk_load.store(false, AtomicOrdering::SeqCst);
+            }
+            Ok::<(), rocksdb::Error>(())
+        })
+        .await;
+        self.maintenance.store(false, AtomicOrdering::SeqCst);
+        result
+            .map_err(ServerError::from)?
+            .map_err(ServerError::from)?;
+        let elapsed_ms = start.elapsed().as_secs_f64() * 1000.0;
+        let after = self.size_estimates();
+        let status = StatusCode::OK;
+        let response = reply::json(&json!({
+            "status": status.as_str(),
+            "elapsed_ms": elapsed_ms,
+            "before": before,
+            "after": after,
+            "bulk_load_ended": bulk_load,
+        }));
+        Ok(reply::with_status(response, status))
+    }
+
+    // POST /admin/flush: write the wal and the memtables of both dbs, and of
+    // the audit column family when there is one, to disk, e.g. before a
+    // filesystem backup
+    async fn flush(self: Arc<Self>) -> Result<impl warp::Reply, warp::Rejection> {
+        let start = Instant::now();
+        let server_clone = Arc::clone(&self);
+        tokio::task::spawn_blocking(move || {
+            for db in [&server_clone.docs, &server_clone.index_db] {
+                db.flush_wal(true)?;
+                db.flush()?;
+            }
+            // flush() only covers the default column family
+            if let Some(cf) = server_clone.docs.cf_handle(AUDIT_CF) {
+                server_clone.docs.flush_cf(cf)?;
+            }
+            Ok::<(), rocksdb::Error>(())
+        })
+        .await
+        .map_err(ServerError::from)?
+        .map_err(ServerError::from)?;
+        let elapsed_ms = start.elapsed().as_secs_f64() * 1000.0;
+        let status = StatusCode::OK;
+        let body = json!({ "status": status.as_str(), "elapsed_ms": elapsed_ms });
+        Ok(reply::with_status(reply::json(&body), status))
+    }
+
+    // POST /admin/purge: remove the tombstones deleted before `before` for good
+    async fn purge(
+        self: Arc<Self>,
+        before: Option<String>,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let before = match before.as_deref().map(parse_rfc3339) {
+            Some(Some(before)) => before,
+            _ => {
+                let error = ApiError::bad_request("before must be an RFC 3339 timestamp");
+                return Ok(error.into_response());
+            }
+        };
+        let _permit = match self.scan_permits.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => return Ok(too_many_scans()),
+        };
+
+        let purgeable = |document: &Value| {
+            let deleted_at = document
+                .get("_deleted_at")
+                .and_then(|v| v.as_str())
+                .and_then(parse_rfc3339);
+            is_tombstone(document) && deleted_at.is_some_and(|at| at < before)
+        };
+        let mut candidates = Vec::new();
+        for entry in self.docs.iterator(IteratorMode::Start) {
+            let (key, value) = entry.map_err(ServerError::from)?;
+            match decode_record(&key, &value) {
+                Some((id, document)) if purgeable(&document) => candidates.push(id),
+                _ => continue,
+            }
+        }
+
+        // shards are locked in ascending order, like in delete_documents, and
+        // each tombstone is read again: an upsert may have revived it since the scan
+        let shards = candidates
+            .iter()
+            .map(|id| self.lock_shard(id))
+            .collect::<BTreeSet<usize>>();
+        let mut guards = Vec::with_capacity(shards.len());
+        for shard in shards {
+            guards.push(self.locks[shard].lock().await);
+        }
+        let mut batch = WriteBatch::default();
+        let mut purged = 0;
+        for id in candidates {
+            let document = match self.get_document_by_id(id.clone())? {
+                Some(document) if purgeable(&document) => document,
+                _ => continue,
+            };
+            batch.delete(&id);
+            self.audit(&mut batch, &id, Some(&document), None);
+            purged += 1;
+        }
+        let write_options = rocksdb::WriteOptions::default();
+        self.docs
+            .write_opt(batch, &write_options)
+            .map_err(ServerError::from)?;
+        self.invalidate_query_cache();
+
+        let status = StatusCode::OK;
+        let body = json!({ "status": status.as_str(), "purged": purged });
+        Ok(reply::with_status(reply::json(&body), status).into_response())
+    }
+
+    // POST /admin/import-file: each line of a server-side ndjson file is stored
+    // as a new document, like POST /docs, in batches of IMPORT_BATCH_SIZE
+    async fn import_file(
+        self: Arc<Self>,
+        request: ImportRequest,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        if self.config.import_dirs.is_empty() {
+            let error = ApiError::new(StatusCode::FORBIDDEN, "set IMPORT_DIRS to import files");
+            return Ok(error.into_response());
+        }
+        let path = match fs::canonicalize(&request.path) {
+            Ok(path) => path,
+            Err(e) => {
+                let error = ApiError::bad_request(format!("{}: {}", request.path, e));
+                return Ok(error.into_response());
+            }
+        };
+        if !self
+            .config
+            .import_dirs
+            .iter()
+            .any(|dir| path.starts_with(dir))
+        {
+            let error = ApiError::new(StatusCode::FORBIDDEN, "path is outside of IMPORT_DIRS");
+            return Ok(error.into_response());
+        }
+        let file = match fs::File::open(&path) {
+            Ok(file) => file,
+            Err(e) => {
+                let error = ApiError::bad_request(format!("{}: {}", request.path, e));
+                return Ok(error.into_response());
+            }
+        };
+
+        let mut imported = 0;
+        let mut skipped = 0;
+        let mut errors = Vec::new();
+        let mut batch = Vec::new();
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(ServerError::from)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut document = match serde_json::from_str::<Value>(&line) {
+                Ok(document) if document.is_object() => document,
+                result => {
+                    let error = match result {
+                        Err(e) => e.to_string(),
+                        Ok(_) => "document must be a JSON object".to_string(),
+                    };
+                    skipped += 1;
+                    if errors.len() < MAX_IMPORT_ERRORS {
+                        errors.push(json!({ "line": i + 1, "error": error }));
+                    }
+                    continue;
+                }
+            };
+            apply_computed_fields(&mut document, &self.config.computed_fields);
+            stamp_document(&mut document, None);
+            batch.push((Uuid::new_v4().to_string(), document));
+            if batch.len() == IMPORT_BATCH_SIZE {
+                imported += Arc::clone(&self).import_batch(&mut batch).await?;
+                tracing::info!(path = %path.display(), line = i + 1, imported, skipped, "importing");
+            }
+        }
+        imported += Arc::clone(&self).import_batch(&mut batch).await?;
+        self.invalidate_query_cache();
+
+        let status = StatusCode::OK;
+        let body = json!({
+            "status": status.as_str(),
+            "imported": imported,
+            "skipped": skipped,
+            "errors": errors,
+        });
+        Ok(reply::with_status(reply::json(&body), status).into_response())
+    }
+
+    // one atomic write for the documents, then their posting lists
+    async fn import_batch(
+        self: Arc<Self>,
+        documents: &mut Vec<(String, Value)>,
+    ) -> Result<usize, ServerError> {
+        let mut batch = WriteBatch::default();
+        for (id, document) in documents.iter() {
+            batch.put(id, serde_json::to_string(document)?);
+            self.audit(&mut batch, id, None, Some(document));
+        }
+        let write_options = rocksdb::WriteOptions::default();
+        self.docs.write_opt(batch, &write_options)?;
+        let count = documents.len();
+        for (id, document) in documents.drain(..) {
+            Arc::clone(&self)
+                .index(&self.index_db, id, document)
+                .await?;
+        }
+        Ok(count)
+    }
+
+    // PUT /kv/:key: store the body as-is, next to the index, without parsing it
+    async fn put_blob(
+        self: Arc<Self>,
+        key: String,
+        body: warp::hyper::body::Bytes,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let write_options = rocksdb::WriteOptions::default();
+        self.index_db
+            .put_opt(blob_key(&key), &body, &write_options)
+            .map_err(ServerError::from)?;
+        let status = StatusCode::OK;
+        let body = json!({ "status": status.as_str(), "key": key, "bytes": body.len() });
+        Ok(reply::with_status(reply::json(&body), status).into_response())
+    }
+
+    // GET /kv/:key: the stored bytes, whatever they are
+    async fn get_blob(self: Arc<Self>, key: String) -> Result<impl warp::Reply, warp::Rejection> {
+        let value = match self
+            .index_db
+            .get(blob_key(&key))
+            .map_err(ServerError::from)?
+        {
+            Some(value) => value,
+            None => return Ok(ApiError::not_found("key not found").into_response()),
+        };
+        let response = reply::with_status(value, StatusCode::OK);
+        let response = reply::with_header(response, "content-type", "application/octet-stream");
+        Ok(response.into_response())
+    }
+
+    // drop every posting list and build them again from the stored documents,
+    // e.g. after documents were written around the index. the new lists are
+    // collected in memory and written in batches; returns the documents indexed
+    fn reindex(&self) -> Result<usize, ServerError> {
+        let start = Instant::now();
+        // posting list keys start with `.`, and so do the history keys of ids
+        // that do, which stay; the hash and blob keys start with `\0`
+        let mut batch = WriteBatch::default();
+        let mode = IteratorMode::From(b".", Direction::Forward);
+        for entry in self.index_db.iterator(mode) {
+            let (key, _) = entry?;
+            if !key.starts_with(b".") {
+                break;
+            }
+            if !is_history_key(&key) {
+                batch.delete(key);
+            }
+        }
+        let write_options = rocksdb::WriteOptions::default();
+        self.index_db.write_opt(batch, &write_options)?;
+
+        let mut posting_lists: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let mut indexed = 0;
+        for entry in self.docs.iterator(IteratorMode::Start) {
+            let (key, value) = entry?;
+            let (id, document) = match decode_record(&key, &value) {
+                Some(record) => record,
+                None => continue,
+            };
+            // tombstones aren't in the posting lists, see `index_lookups`
+            if is_tombstone(&document) || !document.is_object() {
+                continue;
+            }
+            for index_key in get_path_values(&document, "".to_string()) {
+                posting_lists.entry(index_key).or_default().push(id.clone());
+            }
+            indexed += 1;
+            if indexed % REINDEX_LOG_INTERVAL == 0 {
+                tracing::info!(documents = indexed, "reindexing");
+            }
+        }
+        let keys = posting_lists.len();
+        let mut batch = WriteBatch::default();
+        for (index_key, ids) in posting_lists {
+            batch.put(index_key, ids.join(","));
+            if batch.len() == IMPORT_BATCH_SIZE {
+                self.index_db
+                    .write_opt(std::mem::take(&mut batch), &write_options)?;
+            }
+        }
+        self.index_db.write_opt(batch, &write_options)?;
+        self.invalidate_query_cache();
+        tracing::info!(
+            documents = indexed,
+            keys,
+            elapsed_ms = start.elapsed().as_millis() as u64,
+            "reindexed"
+        );
+        Ok(indexed)
+    }
+
+    async fn readiness(self: Arc<Self>) -> Result<impl warp::Reply, warp::Rejection> {
+        // a property read answers from memory, but fails if the db is unusable
+        let responsive = |db: &DB| db.property_int_value("rocksdb.estimate-num-keys").is_ok();
+        let not_ready = if self.config.read_only {
+            Some("server is in read-only mode")
+        } else if self.maintenance.load(AtomicOrdering::SeqCst) {
+            Some("server is in maintenance")
+        } else if !responsive(&self.docs) || !responsive(&self.index_db) {
+            Some("database is not responding")
+        } else {
+            None
+        };
+        match not_ready {
+            Some(reason) => {
+                Ok(ApiError::new(StatusCode::SERVICE_UNAVAILABLE, reason).into_response())
+            }
+            None => {
+                let status = StatusCode::OK;
+                let body = json!({ "status": status.as_str(), "ready": true });
+                Ok(reply::with_status(reply::json(&body), status).into_response())
+            }
+        }
+    }
+
+    // rocksdb's own size estimates for the documents db, in bytes
+    fn size_estimates(&self) -> Value {
+        let property = |name: &str| self.docs.property_int_value(name).ok().flatten();
+        json!({
+            "live_data_size": property("rocksdb.estimate-live-data-size"),
+            "sst_files_size": property("rocksdb.total-sst-files-size"),
+        })
+    }
+
+    async fn version(self: Arc<Self>) -> Result<impl warp::Reply, warp::Rejection> {
+        let status = StatusCode::OK;
+        let body = json!({
+            "status": status.as_str(),
+            "version": env!("CARGO_PKG_VERSION"),
+            "rocksdb": rocksdb_version(&self.db_path),
+            "git_commit": option_env!("GIT_COMMIT"),
+        });
+        Ok(reply::with_status(reply::json(&body), status))
+    }
+
+    async fn rocksdb_properties(self: Arc<Self>) -> Result<impl warp::Reply, warp::Rejection> {
+        let status = StatusCode::OK;
+        let body = json!({
+            "status": status.as_str(),
+            "docs": db_properties(&self.docs),
+            "index": db_properties(&self.index_db),
+        });
+        Ok(reply::with_status(reply::json(&body), status))
+    }
+}
+
+// the rust bindings don't expose the library version, but rocksdb writes it
+// into the OPTIONS file of every db it opens
+fn rocksdb_version(db_path: &Path) -> Option<String> {
+    let latest = fs::read_dir(db_path)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let number = name.strip_prefix("OPTIONS-")?.parse::<u64>().ok()?;
+            Some((number, entry.path()))
+        })
+        .max()?;
+    let options = fs::read_to_string(latest.1).ok()?;
+    options.lines().find_map(|line| {
+        let value = line.trim().strip_prefix("rocksdb_version=")?;
+        Some(value.trim().to_string())
+    })
+}
+
+// integer properties of a rocksdb instance, for diagnosing stalls
+const INT_PROPERTIES: &[&str] = &[
+    "rocksdb.num-running-compactions",
+    "rocksdb.num-running-flushes",
+    "rocksdb.compaction-pending",
+    "rocksdb.mem-table-flush-pending",
+    "rocksdb.is-write-stopped",
+    "rocksdb.actual-delayed-write-rate",
+    "rocksdb.background-errors",
+    "rocksdb.estimate-num-keys",
+    "rocksdb.estimate-live-data-size",
+    "rocksdb.estimate-pending-compaction-bytes",
+    "rocksdb.total-sst-files-size",
+    "rocksdb.live-sst-files-size",
+    "rocksdb.cur-size-all-mem-tables",
+    "rocksdb.size-all-mem-tables",
+    "rocksdb.num-immutable-mem-table",
+    "rocksdb.num-snapshots",
+    "rocksdb.num-live-versions",
+    "rocksdb.block-cache-usage",
+];
+
+// text properties; `rocksdb.stats` is the multi-line summary rocksdb also logs
+const TEXT_PROPERTIES: &[&str] = &["rocksdb.stats", "rocksdb.levelstats"];
+
+// property name -> value; properties this rocksdb doesn't know are null
+fn db_properties(db: &DB) -> serde_json::Map<String, Value> {
+    let mut properties = serde_json::Map::new();
+    for name in INT_PROPERTIES {
+        let value = db.property_int_value(*name).ok().flatten();
+        properties.insert(name.to_string(), json!(value));
+    }
+    for name in TEXT_PROPERTIES {
+        let value = db.property_value(*name).ok().flatten();
+        properties.insert(name.to_string(), json!(value));
+    }
+    properties
+}
+
+// `?limit=` and `?offset=` paging of search results
+struct Page {
+    limit: Option<usize>,
+    offset: usize,
+    // `?after=<next_cursor>`: only documents with a greater id
+    after: Option<String>,
+}
+
+// documents a search read and how many of them matched, for the slow-query log
+#[derive(Default)]
+struct ScanStats {
+    scanned: usize,
+    matched: usize,
+}
+
+// MAX_RESPONSE_BYTES of a scan: the matches behind the first `skip`, the ones
+// a page shows, count as serialized `{"id", "body"}` entries
+struct ByteBudget {
+    skip: usize,
+    bytes: usize,
+    used: usize,
+}
+
+impl ByteBudget {
+    fn new(skip: usize, bytes: usize) -> Self {
+        Self {
+            skip,
+            bytes,
+            used: 0,
+        }
+    }
+
+    // counts the match just pushed to `documents`. true once the page is over
+    // the budget with more than one entry, so trimming drops the last one and
+    // marks the page truncated
+    fn spend(&mut self, documents: &[Value]) -> bool {
+        match documents.last() {
+            Some(document) if documents.len() > self.skip => {
+                self.used += document.to_string().len() + 1;
+                self.is_spent() && documents.len() > self.skip + 1
+            }
+            _ => false,
+        }
+    }
+
+    fn is_spent(&self) -> bool {
+        self.used > self.bytes
+    }
+}
+
+// what of each matching document a search returns
+#[derive(Default)]
+struct Projection {
+    // only these paths of each body; None returns whole bodies
+    fields: Option<Vec<String>>,
+    // only the ids, no bodies
+    ids_only: bool,
+    // an object of arrays keyed by the value at this path
+    group_by: Option<Vec<String>>,
+    // bodies in an object keyed by id, instead of an array of `{"id", "body"}`
+    as_map: bool,
+    // with `group_by`, only the number of matches in each group
+    group_count: bool,
+    // only the first match for each value at this path, after sorting
+    distinct_by: Option<Vec<String>>,
+}
+
+impl Page {
+    fn from_params(params: &HashMap<String, String>) -> Result<Self, ApiError> {
+        let parse = |name: &str| match params.get(name) {
+            None => Ok(None),
+            Some(v) => v.parse::<usize>().map(Some).map_err(|_| {
+                ApiError::bad_request(format!("{} must be a non-negative integer", name))
+            }),
+        };
+        let after = match params.get("after").filter(|v| !v.is_empty()) {
+            None => None,
+            Some(cursor) => Some(decode_cursor(cursor).ok_or_else(|| {
+                ApiError::bad_request("after must be a next_cursor of an earlier page")
+            })?),
+        };
+        Ok(Self {
+            limit: parse("limit")?,
+            offset: parse("offset")?.unwrap_or(0),
+            after,
+        })
+    }
+}
+
+// cursors are the hex of the last id, so they are safe in a url whatever the id
+fn encode_cursor(id: &str) -> String {
+    id.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_cursor(cursor: &str) -> Option<String> {
+    let bytes = cursor
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [hi, lo] => u8::from_str_radix(std::str::from_utf8(&[*hi, *lo]).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+// `?sort=path` (ascending) or `?sort=-path` (descending) ordering of search results
+#[derive(Clone)]
+struct Sort {
+    path: Vec<String>,
+    descending: bool,
+}
+
+impl Sort {
+    fn by_updated_at() -> Self {
+        Self {
+            path: vec!["_updated_at".to_string()],
+            descending: false,
+        }
+    }
+
+    fn from_params(params: &HashMap<String, String>, separator: char) -> Option<Self> {
+        Self::parse(params.get("sort")?, separator)
+    }
+
+    fn parse(sort: &str, separator: char) -> Option<Self> {
+        let sort = sort.trim();
+        let (field, descending) = match sort.strip_prefix('-') {
+            Some(field) => (field, true),
+            None => (sort, false),
+        };
+        if field.is_empty() {
+            return None;
+        }
+        Some(Self {
+            path: split_path(field, separator),
+            descending,
+        })
+    }
+
+    // sort `{"id", "body"}` results; documents without the field go last either way
+    fn apply(&self, documents: &mut [Value]) {
+        let timestamps =
+            matches!(self.path.as_slice(), [f] if f == "_created_at" || f == "_updated_at");
+        let key = |document: &Value| {
+            let value = get_value_from_doc(document["body"].clone(), &self.path);
+            if timestamps {
+                // compare instants, not strings, so offsets and precision don't matter
+                return value.as_str().and_then(parse_rfc3339).map(Value::from);
+            }
+            Some(value).filter(|v| !v.is_null())
+        };
+        documents.sort_by_cached_key(|document| SortKey(key(document), self.descending));
+    }
+}
+
+// `None` (no value) sorts after every value, in both directions
+#[derive(PartialEq, Eq)]
+struct SortKey(Option<Value>, bool);
+
+impl PartialOrd for SortKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SortKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (&self.0, &other.0) {
+            (Some(a), Some(b)) if self.1 => compare_values(b, a),
+            (Some(a), Some(b)) => compare_values(a, b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+    }
+}
+
+// add `Server-Timing` and `X-Response-Time-Ms` headers for the time since `start`
+fn with_timing(mut response: reply::Response, start: Instant) -> reply::Response {
+    let elapsed_ms = start.elapsed().as_secs_f64() * 1000.0;
+    let headers = response.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(&format!("handler;dur={:.3}", elapsed_ms)) {
+        headers.insert("server-timing", value);
+    }
+    if let Ok(value) = HeaderValue::from_str(&format!("{:.3}", elapsed_ms)) {
+        headers.insert("x-response-time-ms", value);
+    }
+    response
+}
+
+const MAX_ID_LENGTH: usize = 128;
+
+// ids are generated uuids or chosen by clients (PUT with upsert). anything
+// url-safe of a sane length is accepted, so both kinds pass.
+fn check_id(id: &str) -> Result<(), &'static str> {
+    if id.len() > MAX_ID_LENGTH {
+        return Err("longer than 128 characters");
+    }
+    let safe = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '~');
+    if !id.chars().all(safe) {
+        return Err("only ASCII letters, digits and - _ . ~ are allowed");
+    }
+    Ok(())
+}
+
+// the `:id` segment of `/docs/:id/...`, checked before the handler runs
+fn doc_id() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Copy {
+    warp::path::param::<String>().and_then(|id: String| async move {
+        match check_id(&id) {
+            Ok(()) => Ok(id),
+            Err(message) => Err(warp::reject::custom(InvalidId(message))),
+        }
+    })
+}
+
+// 400 for a search the index can't narrow down, with --reject-full-scans
+fn full_scan_rejected() -> ApiError {
+    ApiError::bad_request(
+        "query needs a full scan, which --reject-full-scans forbids; add an `=` condition on a string or boolean field",
+    )
+}
+
+// csv and ndjson bodies have no place for the `truncated` flag
+fn with_truncated(mut response: reply::Response, truncated: bool) -> reply::Response {
+    if truncated {
+        response
+            .headers_mut()
+            .insert("x-truncated", HeaderValue::from_static("true"));
+    }
+    response
+}
+
+fn with_last_modified(mut response: reply::Response, millis: Option<i64>) -> reply::Response {
+    if let Some(value) = millis.and_then(|m| HeaderValue::from_str(&format_http_date(m)).ok()) {
+        response.headers_mut().insert("last-modified", value);
+    }
+    response
+}
+
+// 503 for a scan that found all scan permits taken
+fn too_many_scans() -> reply::Response {
+    let mut response = ApiError::new(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "too many concurrent searches, retry later",
+    )
+    .into_response();
+    response
+        .headers_mut()
+        .insert("retry-after", HeaderValue::from_static("1"));
+    response
+}
+
+// cors for one group of routes; `origins` of None allows any origin
+fn cors(origins: &Option<Vec<String>>, methods: &[&str]) -> warp::cors::Builder {
+    let cors = warp::cors()
+        .allow_methods(methods.iter().copied())
+        .allow_headers(["content-type", "accept", "x-api-key"]);
+    match origins {
+        Some(origins) => cors.allow_origins(origins.iter().map(|origin| origin.as_str())),
+        None => cors.allow_any_origin(),
+    }
+}
+
+// every route, listed in the 404 of an unknown path; `:id` is any segment
+const ROUTES: &[(&str, &str)] = &[
+    ("GET", "/docs"),
+    ("POST", "/docs"),
+    ("POST", "/docs/search"),
+    ("POST", "/docs/mget"),
+    ("POST", "/docs/delete"),
+    ("GET", "/docs/distinct"),
+    ("GET", "/docs/schema"),
+    ("GET", "/docs/explain"),
+    ("GET", "/docs/size"),
+    ("GET", "/docs/:id"),
+    ("PUT", "/docs/:id"),
+    ("PATCH", "/docs/:id"),
+    ("GET", "/docs/:id/field"),
+    ("GET", "/docs/:id/modified"),
+    ("POST", "/docs/:id/incr"),
+    ("GET", "/docs/:id/versions"),
+    ("POST", "/docs/:id/restore/:version"),
+    ("POST", "/admin/compact"),
+    ("POST", "/admin/flush"),
+    ("POST", "/admin/purge"),
+    ("POST", "/admin/import-file"),
+    ("GET", "/admin/rocksdb-props"),
+    ("GET", "/admin/audit"),
+    ("PUT", "/kv/:key"),
+    ("GET", "/kv/:key"),
+    ("GET", "/version"),
+    ("GET", "/livez"),
+    ("GET", "/readyz"),
+];
+
+fn is_known_path(path: &str) -> bool {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    ROUTES.iter().any(|(_, route)| {
+        let pattern: Vec<&str> = route.split('/').filter(|s| !s.is_empty()).collect();
+        pattern.len() == segments.len()
+            && pattern
+                .iter()
+                .zip(&segments)
+                .all(|(p, s)| p.starts_with(':') || p == s)
+    })
+}
+
+// the last route: a path no route knows gets a 404 listing the routes. without
+// it, warp answers 405 as soon as a route for another method rejected it.
+// known paths are left to the rejections of their own routes.
+async fn route_not_found(
+    prefix: String,
+    method: Method,
+    path: warp::path::FullPath,
+) -> Result<reply::Response, warp::Rejection> {
+    let route = path
+        .as_str()
+        .strip_prefix(prefix.as_str())
+        .filter(|rest| rest.is_empty() || rest.starts_with('/'));
+    if route.is_some_and(is_known_path) {
+        return Err(warp::reject::not_found());
+    }
+    let status = StatusCode::NOT_FOUND;
+    let routes = ROUTES
+        .iter()
+        .map(|(method, route)| format!("{} {}{}", method, prefix, route))
+        .collect::<Vec<String>>();
+    let body = json!({
+        "error": {
+            "message": format!("route not found: {} {}", method, path.as_str()),
+            "code": status.as_u16(),
+            "routes": routes,
+        }
+    });
+    Ok(reply::with_status(reply::json(&body), status).into_response())
+}
+
+// collapse duplicate slashes and drop a trailing slash: "//docs//abc/" -> "/docs/abc"
+fn canonical_path(path: &str) -> String {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    format!("/{}", segments.join("/"))
+}
+
+// fallback for requests no route matched: send non-canonical paths to their canonical
+// form with 308 so the method and body are kept, everything else stays a 404
+async fn redirect_to_canonical(
+    path: warp::path::FullPath,
+    query: String,
+) -> Result<reply::Response, warp::Rejection> {
+    let canonical = canonical_path(path.as_str());
+    if canonical == path.as_str() {
+        return Err(warp::reject::not_found());
+    }
+    let location = if query.is_empty() {
+        canonical
+    } else {
+        format!("{}?{}", canonical, query)
+    };
+    let location = HeaderValue::from_str(&location).map_err(|_| warp::reject::not_found())?;
+    let mut response = reply::with_status(reply(), StatusCode::PERMANENT_REDIRECT).into_response();
+    response.headers_mut().insert("location", location);
+    Ok(response)
+}
+
+// every error response has the shape {"error": {"message": ..., "code": ...}}
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, message)
+    }
+
+    fn internal(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, message)
+    }
+}
+
+// set once at startup from `Config::expose_internal_errors`; errors are
+// turned into responses in places that don't see the config
+static EXPOSE_INTERNAL_ERRORS: AtomicBool = AtomicBool::new(false);
+
+impl warp::Reply for ApiError {
+    fn into_response(self) -> reply::Response {
+        if self.status != StatusCode::INTERNAL_SERVER_ERROR {
+            let body = json!({
+                "error": {
+                    "message": self.message,
+                    "code": self.status.as_u16(),
+                }
+            });
+            return reply::with_status(reply::json(&body), self.status).into_response();
+        }
+        // the full message is logged, so a client can report the id instead
+        let correlation_id = Uuid::new_v4().to_string();
+        tracing::error!(
+            correlation_id = %correlation_id,
+            status = self.status.as_u16(),
+            error = %self.message,
+            "internal error"
+        );
+        let message = if EXPOSE_INTERNAL_ERRORS.load(AtomicOrdering::Relaxed) {
+            self.message
+        } else {
+            "internal server error".to_string()
+        };
+        let body = json!({
+            "error": {
+                "message": message,
+                "code": self.status.as_u16(),
+                "correlation_id": correlation_id,
+            }
+        });
+        reply::with_status(reply::json(&body), self.status).into_response()
+    }
+}
+
+// what can go wrong below the http layer
+#[derive(Debug)]
+enum ServerError {
+    Db(rocksdb::Error),
+    Json(serde_json::Error),
+    Utf8(std::string::FromUtf8Error),
+    Io(std::io::Error),
+    // a blocking task that panicked or was cancelled
+    Task(tokio::task::JoinError),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerError::Db(e) => write!(f, "database error: {}", e),
+            ServerError::Json(e) => write!(f, "invalid json: {}", e),
+            ServerError::Utf8(e) => write!(f, "invalid utf-8: {}", e),
+            ServerError::Io(e) => write!(f, "i/o error: {}", e),
+            ServerError::Task(e) => write!(f, "background task failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ServerError::Db(e) => Some(e),
+            ServerError::Json(e) => Some(e),
+            ServerError::Utf8(e) => Some(e),
+            ServerError::Io(e) => Some(e),
+            ServerError::Task(e) => Some(e),
+        }
+    }
+}
+
+impl From<rocksdb::Error> for ServerError {
+    fn from(e: rocksdb::Error) -> Self {
+        ServerError::Db(e)
+    }
+}
+
+impl From<serde_json::Error> for ServerError {
+    fn from(e: serde_json::Error) -> Self {
+        ServerError::Json(e)
+    }
+}
+
+impl From<std::string::FromUtf8Error> for ServerError {
+    fn from(e: std::string::FromUtf8Error) -> Self {
+        ServerError::Utf8(e)
+    }
+}
+
+impl From<std::io::Error> for ServerError {
+    fn from(e: std::io::Error) -> Self {
+        ServerError::Io(e)
+    }
+}
+
+impl From<tokio::task::JoinError> for ServerError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        ServerError::Task(e)
+    }
+}
+
+impl From<ServerError> for ApiError {
+    fn from(e: ServerError) -> Self {
+        ApiError::internal(e.to_string())
+    }
+}
+
+// also makes it a `warp::Rejection`, so handlers can use `?`;
+// `handle_rejection` turns it into a 500
+impl warp::reject::Reject for ServerError {}
+
+#[derive(Debug)]
+struct InvalidId(&'static str);
+
+impl warp::reject::Reject for InvalidId {}
+
+#[derive(Debug)]
+struct RateLimited {
+    retry_after: Duration,
+}
+
+impl warp::reject::Reject for RateLimited {}
+
+#[derive(Debug)]
+struct ReadOnlyMode;
+
+impl warp::reject::Reject for ReadOnlyMode {}
+
+#[derive(Debug)]
+struct AdminDisabled;
+
+impl warp::reject::Reject for AdminDisabled {}
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+// turn warp's own rejections (bad body, wrong method, ...) into `ApiError`s
+async fn handle_rejection(err: warp::Rejection) -> Result<impl warp::Reply, Infallible> {
+    if let Some(limited) = err.find::<RateLimited>() {
+        let mut response =
+            ApiError::new(StatusCode::TOO_MANY_REQUESTS, "rate limit exceeded").into_response();
+        // whole seconds, rounded up so the retry finds a token
+        let retry_after = limited.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+        response
+            .headers_mut()
+            .insert("retry-after", HeaderValue::from(retry_after));
+        return Ok(response);
+    }
+    let error = if let Some(e) = err.find::<warp::filters::cors::CorsForbidden>() {
+        ApiError::new(StatusCode::FORBIDDEN, e.to_string())
+    } else if let Some(InvalidId(message)) = err.find::<InvalidId>() {
+        ApiError::bad_request(format!("invalid document id: {}", message))
+    } else if let Some(e) = err.find::<ServerError>() {
+        ApiError::internal(e.to_string())
+    } else if err.find::<ReadOnlyMode>().is_some() {
+        ApiError::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "server is in read-only mode, writes are disabled",
+        )
+    } else if err.find::<AdminDisabled>().is_some() {
+        ApiError::new(
+            StatusCode::FORBIDDEN,
+            "admin api is disabled, set ADMIN_API_KEY to enable it",
+        )
+    } else if err.find::<Unauthorized>().is_some() {
+        ApiError::new(StatusCode::UNAUTHORIZED, "missing or invalid X-API-Key")
+    } else if err.is_not_found() {
+        ApiError::not_found("route not found")
+    } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        ApiError::bad_request(format!("Invalid body: {}", e))
+    } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+        ApiError::bad_request(e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::MissingHeader>() {
+        ApiError::bad_request(e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::InvalidHeader>() {
+        ApiError::bad_request(e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::UnsupportedMediaType>() {
+        ApiError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::PayloadTooLarge>() {
+        ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::LengthRequired>() {
+        ApiError::new(StatusCode::LENGTH_REQUIRED, e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::MethodNotAllowed>() {
+        ApiError::new(StatusCode::METHOD_NOT_ALLOWED, e.to_string())
+    } else {
+        ApiError::internal(format!("Unhandled rejection: {:?}", err))
+    };
+    Ok(error.into_response())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ResultFormat {
+    Json,
+    Ndjson,
+    Csv,
+}
+
+impl ResultFormat {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "json" => Some(ResultFormat::Json),
+            "ndjson" => Some(ResultFormat::Ndjson),
+            "csv" => Some(ResultFormat::Csv),
+            _ => None,
+        }
+    }
+
+    // `?format=` wins over the Accept header, which wins over DEFAULT_FORMAT.
+    // None for an unknown `?format=`, like an unknown DEFAULT_FORMAT is an error
+    fn negotiate(format: Option<&String>, accept: Option<&String>, default: Self) -> Option<Self> {
+        if let Some(format) = format {
+            return ResultFormat::from_name(format);
+        }
+        Some(match accept {
+            Some(accept) if accept.contains("text/csv") => ResultFormat::Csv,
+            Some(accept) if accept.contains("application/x-ndjson") => ResultFormat::Ndjson,
+            Some(accept) if accept.contains("application/json") => ResultFormat::Json,
+            _ => default,
+        })
+    }
+}
+
+// a comma-separated list of dotted paths; an empty list means whole documents
+fn parse_fields(fields: &str) -> Option<Vec<String>> {
+    let fields = fields
+        .split(',')
+        .map(|field| field.trim().to_string())
+        .filter(|field| !field.is_empty())
+        .collect::<Vec<String>>();
+    if fields.is_empty() {
+        None
+    } else {
+        Some(fields)
+    }
+}
+
+// copy of `body` with only the given paths; missing paths are left out
+fn project_fields(body: &Value, fields: &[String], separator: char) -> Value {
+    let mut projected = json!({});
+    for field in fields {
+        let parts = split_path(field, separator);
+        let value = get_value_from_doc(body.clone(), &parts);
+        if !value.is_null() {
+            set_value_in_doc(&mut projected, &parts, value);
+        }
+    }
+    projected
+}
+
+// `?distinct_by=`: drop the `{"id", "body"}` results whose value at `path`
+// an earlier one had. documents without the field are all kept
+fn keep_first_per_value(documents: &mut Vec<Value>, path: &[String]) {
+    let mut seen = HashSet::new();
+    documents.retain(|document| {
+        let value = get_value_from_doc(document["body"].clone(), path);
+        value.is_null() || seen.insert(value.to_string())
+    });
+}
+
+// bucket of a document for `group_by`: strings by themselves, other values as
+// json, and documents without the field in MISSING_GROUP
+fn group_name(body: &Value, path: &[String]) -> String {
+    match get_value_from_doc(body.clone(), path) {
+        Value::Null => MISSING_GROUP.to_string(),
+        Value::String(s) => s,
+        value => value.to_string(),
+    }
+}
+
+const MISSING_GROUP: &str = "_missing";
+
+// `values[i]` goes into the group `names[i]`, keeping the order within a group
+fn group_values(values: Vec<Value>, names: Vec<String>) -> BTreeMap<String, Vec<Value>> {
+    let mut groups: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    for (value, name) in values.into_iter().zip(names) {
+        groups.entry(name).or_default().push(value);
+    }
+    groups
+}
+
+// a stored record as (id, document). a malformed one is logged and skipped,
+// so it can't fail every scan that passes it
+fn decode_record(key: &[u8], value: &[u8]) -> Option<(String, Value)> {
+    let id = match std::str::from_utf8(key) {
+        Ok(id) => id.to_string(),
+        Err(e) => {
+            let key = String::from_utf8_lossy(key);
+            tracing::warn!(key = %key, error = %e, "skipping a record whose id isn't utf-8");
+            return None;
+        }
+    };
+    match serde_json::from_slice(value) {
+        Ok(document) => Some((id, document)),
+        Err(e) => {
+            tracing::warn!(id = %id, error = %e, "skipping a record that isn't json");
+            None
+        }
+    }
+}
+
+// flatten the top-level fields of each `{"id", "body"}` entry into csv rows.
+// the header is `id` followed by the union of all body keys.
+fn documents_to_csv(documents: &[Value]) -> String {
+    let mut columns = BTreeSet::new();
+    for document in documents {
+        if let Some(body) = document["body"].as_object() {
+            columns.extend(body.keys().cloned());
+        }
+    }
+
+    let mut csv = String::new();
+    let header = std::iter::once("id".to_string())
+        .chain(columns.iter().cloned())
+        .map(|column| csv_escape(&column))
+        .collect::<Vec<String>>();
+    csv.push_str(&header.join(","));
+    csv.push_str("\r\n");
+
+    for document in documents {
+        let mut row = vec![csv_escape(&csv_cell(&document["id"]))];
+        for column in columns.iter() {
+            row.push(csv_escape(&csv_cell(&document["body"][column])));
+        }
+        csv.push_str(&row.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+fn csv_cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        // numbers, booleans and nested objects/arrays are written as json
+        _ => value.to_string(),
+    }
+}
+
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// how the segments of a path are matched against object keys
+#[derive(Debug, Clone, Copy)]
+struct PathOptions {
+    // `?ci_keys=true`: `name` also finds `Name`. an exact match wins, otherwise
+    // the first key in key order that differs only by case
+    case_insensitive: bool,
+    // paths with more segments resolve to null instead of being followed
+    max_depth: usize,
+    // what query keys are split at
+    separator: char,
+}
+
+const DEFAULT_MAX_PATH_DEPTH: usize = 32;
+
+impl Default for PathOptions {
+    fn default() -> Self {
+        Self {
+            case_insensitive: false,
+            max_depth: DEFAULT_MAX_PATH_DEPTH,
+            separator: '.',
+        }
+    }
+}
+
+// the object keys of a request path like `director.name`
+fn split_path(path: &str, separator: char) -> Vec<String> {
+    path.split(separator).map(|s| s.to_string()).collect()
+}
+
+fn get_value_from_doc(doc: Value, parts: &[String]) -> Value {
+    get_value_with_options(doc, parts, PathOptions::default())
+}
+
+fn get_value_with_options(doc: Value, parts: &[String], options: PathOptions) -> Value {
+    if parts.len() > options.max_depth {
+        return Value::Null;
+    }
+    let mut current = &doc;
+
+    for (i, part) in parts.iter().enumerate() {
+        let part = part.trim();
+        if part.is_empty() {
+            continue;
+        }
+        // arrays and strings have a virtual `length` field, e.g. `tags.length:>3`
+        if part == "length" {
+            if let Some(length) = value_length(current) {
+                if parts[i + 1..].iter().all(|p| p.trim().is_empty()) {
+                    return Value::from(length);
+                }
+                return Value::Null;
+            }
+        }
+        // arrays of numbers have virtual aggregates, e.g. `scores.max:>85`
+        if let Value::Array(items) = current {
+            if let Some(aggregate) = Aggregate::from_name(part) {
+                if parts[i + 1..].iter().all(|p| p.trim().is_empty()) {
+                    return aggregate.apply(items);
+                }
+                return Value::Null;
+            }
+        }
+        let value = match current {
+            Value::Object(map) if options.case_insensitive => map.get(part).or_else(|| {
+                let part = part.to_lowercase();
+                map.iter()
+                    .find(|(key, _)| key.to_lowercase() == part)
+                    .map(|(_, value)| value)
+            }),
+            _ => current.get(part),
+        };
+
+        if value.is_none() {
+            return Value::Null;
+        }
+        current = value.unwrap();
+    }
+    current.clone()
+}
+
+// count the json type of every field of `map` under its dotted path, walking
+// nested objects up to `depth` levels; arrays are counted but not entered
+fn collect_field_types(
+    map: &serde_json::Map<String, Value>,
+    prefix: &str,
+    depth: usize,
+    separator: char,
+    fields: &mut BTreeMap<String, BTreeMap<&'static str, usize>>,
+) {
+    if depth == 0 {
+        return;
+    }
+    for (key, value) in map {
+        let path = format!("{}{}", prefix, key);
+        let kind = match value {
+            Value::Null => "null",
+            Value::Bool(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Array(_) => "array",
+            Value::Object(_) => "object",
+        };
+        *fields
+            .entry(path.clone())
+            .or_default()
+            .entry(kind)
+            .or_insert(0) += 1;
+        if let Value::Object(nested) = value {
+            let prefix = format!("{}{}", path, separator);
+            collect_field_types(nested, &prefix, depth - 1, separator, fields);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Aggregate {
+    Max,
+    Min,
+    Avg,
+    Sum,
+}
+
+impl Aggregate {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "max" => Some(Aggregate::Max),
+            "min" => Some(Aggregate::Min),
+            "avg" => Some(Aggregate::Avg),
+            "sum" => Some(Aggregate::Sum),
+            _ => None,
+        }
+    }
+
+    // null unless every item is a number; min, max and avg of no items too.
+    // the sum of integers stays an integer, so it compares exactly
+    fn apply(self, items: &[Value]) -> Value {
+        let numbers = match items
+            .iter()
+            .map(|v| v.as_f64())
+            .collect::<Option<Vec<f64>>>()
+        {
+            Some(numbers) => numbers,
+            None => return Value::Null,
+        };
+        match self {
+            Aggregate::Sum => match items
+                .iter()
+                .try_fold(0i64, |sum, v| sum.checked_add(v.as_i64()?))
+            {
+                Some(sum) => Value::from(sum),
+                None => Value::from(numbers.iter().sum::<f64>()),
+            },
+            _ if numbers.is_empty() => Value::Null,
+            Aggregate::Avg => Value::from(numbers.iter().sum::<f64>() / numbers.len() as f64),
+            Aggregate::Max | Aggregate::Min => {
+                let mut best = 0;
+                for (i, n) in numbers.iter().enumerate() {
+                    let better = match self {
+                        Aggregate::Max => *n > numbers[best],
+                        _ => *n < numbers[best],
+                    };
+                    if better {
+                        best = i;
+                    }
+                }
+                items[best].clone()
+            }
+        }
+    }
+}
+
+// number of elements of an array, or of characters (not bytes) of a string
+fn value_length(value: &Value) -> Option<usize> {
+    match value {
+        Value::Array(items) => Some(items.len()),
+        Value::String(s) => Some(s.chars().count()),
+        _ => None,
+    }
+}
+
+// set `_created_at` and `_updated_at` on a document that is about to be stored.
+// `old` is the stored version it replaces, whose creation time is kept.
+fn stamp_document(doc: &mut Value, old: Option<&Value>) {
+    let now = Value::from(format_rfc3339(now_millis()));
+    let created_at = old
+        .and_then(|old| old.get("_created_at"))
+        .cloned()
+        .unwrap_or_else(|| now.clone());
+    if let Some(doc) = doc.as_object_mut() {
+        doc.insert("_created_at".to_string(), created_at);
+        doc.insert("_updated_at".to_string(), now);
+        // only a delete makes a tombstone
+        doc.remove("_deleted");
+        doc.remove("_deleted_at");
+    }
+}
+
+// index db key of the content hash of `doc`, leaving out the fields set by
+// the server. index keys start with `.`, so this can't collide with them
+fn content_hash_key(doc: &Value) -> String {
+    let mut content = doc.clone();
+    if let Some(content) = content.as_object_mut() {
+        for field in ["_created_at", "_updated_at", "_deleted", "_deleted_at"] {
+            content.remove(field);
+        }
+    }
+    // serialized like the stored documents, with the keys of every object
+    // sorted, so equal documents hash the same regardless of key order
+    let hash = Sha1::digest(content.to_string().as_bytes());
+    format!("\0sha1:{:x}", hash)
+}
+
+// prior versions live in the index db under `id\0version`, zero-padded so
+// they sort by number
+fn history_key(id: &str, version: u64) -> String {
+    format!("{}\0{:020}", id, version)
+}
+
+// whether an index db key is a `history_key`. field names may contain `\0`
+// too, but a posting list key has its `:` and value after the field name,
+// and serialized json never contains a `\0`
+fn is_history_key(key: &[u8]) -> bool {
+    match key.iter().rposition(|&byte| byte == 0) {
+        Some(at) => {
+            let version = &key[at + 1..];
+            version.len() == 20 && version.iter().all(u8::is_ascii_digit)
+        }
+        None => false,
+    }
+}
+
+// audit entries live in their own column family, milliseconds first, so the
+// entries sort by time and `since` is a prefix
+fn audit_key(millis: i64, sequence: u64) -> String {
+    format!("{:016}-{:016}", millis, sequence)
+}
+
+// the index db key of a blob of the kv routes; ids can't start with `\0`, so
+// it can't collide with a version, and reindexing leaves it alone
+fn blob_key(key: &str) -> String {
+    format!("\0kv:{}", key)
+}
+
+// `doc` marked deleted, as stored by a delete with --soft-delete
+fn tombstone_of(doc: &Value) -> Value {
+    let mut tombstone = doc.clone();
+    let now = Value::from(format_rfc3339(now_millis()));
+    if let Some(tombstone) = tombstone.as_object_mut() {
+        tombstone.insert("_deleted".to_string(), Value::Bool(true));
+        tombstone.insert("_deleted_at".to_string(), now.clone());
+        tombstone.insert("_updated_at".to_string(), now);
+    }
+    tombstone
+}
+
+fn is_tombstone(doc: &Value) -> bool {
+    doc.get("_deleted") == Some(&Value::Bool(true))
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+// milliseconds since the unix epoch as an RFC 3339 timestamp in UTC,
+// e.g. "2023-06-01T12:30:00.000Z"
+fn format_rfc3339(millis: i64) -> String {
+    let (year, month, day) = civil_from_days(millis.div_euclid(86_400_000));
+    let ms = millis.rem_euclid(86_400_000);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+// RFC 3339 timestamp ("YYYY-MM-DDTHH:MM:SS[.frac](Z|+HH:MM|-HH:MM)") to
+// milliseconds since the unix epoch. fractions beyond milliseconds are cut off.
+fn parse_rfc3339(s: &str) -> Option<i64> {
+    let digits = |from: usize, to: usize| -> Option<i64> {
+        let part = s.get(from..to)?;
+        if !part.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        part.parse().ok()
+    };
+    let bytes = s.as_bytes();
+    if bytes.len() < 20
+        || bytes[4] != b'-'
+        || bytes[7] != b'-'
+        || !matches!(bytes[10], b'T' | b't' | b' ')
+        || bytes[13] != b':'
+        || bytes[16] != b':'
+    {
+        return None;
+    }
+    let (year, month, day) = (digits(0, 4)?, digits(5, 7)?, digits(8, 10)?);
+    let (hour, minute, second) = (digits(11, 13)?, digits(14, 16)?, digits(17, 19)?);
+    // a second of 60 is a leap second
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+
+    let mut rest = &s[19..];
+    let mut millis = 0;
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let len = fraction.bytes().take_while(|b| b.is_ascii_digit()).count();
+        if len == 0 {
+            return None;
+        }
+        let padded = format!("{:0<3}", &fraction[..len.min(3)]);
+        millis = padded.parse::<i64>().ok()?;
+        rest = &fraction[len..];
+    }
+    let offset_minutes = match rest {
+        "Z" | "z" => 0,
+        _ if rest.len() == 6 && rest.as_bytes()[3] == b':' => {
+            let sign = match rest.as_bytes()[0] {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            let hours = rest[1..3].parse::<i64>().ok()?;
+            let minutes = rest[4..6].parse::<i64>().ok()?;
+            sign * (hours * 60 + minutes)
+        }
+        _ => return None,
+    };
+
+    let days = days_from_civil(year, month, day);
+    let seconds = days * 86_400 + hour * 3600 + minute * 60 + second - offset_minutes * 60;
+    Some(seconds * 1000 + millis)
+}
+
+// days since 1970-01-01 of a proleptic gregorian date (howard hinnant's algorithm)
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// `Sun, 06 Nov 1994 08:49:37 GMT`, the date format of http headers
+fn format_http_date(millis: i64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let days = millis.div_euclid(86_400_000);
+    let (year, month, day) = civil_from_days(days);
+    let secs = millis.rem_euclid(86_400_000) / 1000;
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[days.rem_euclid(7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+// inverse of `format_http_date`, in milliseconds since the unix epoch. the
+// obsolete rfc 850 and asctime forms are not accepted.
+fn parse_http_date(s: &str) -> Option<i64> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let parts = s.trim().split(' ').collect::<Vec<&str>>();
+    let (day, month, year, time) = match parts.as_slice() {
+        [weekday, day, month, year, time, "GMT"]
+            if weekday.len() == 4 && weekday.ends_with(',') =>
+        {
+            (*day, *month, *year, *time)
+        }
+        _ => return None,
+    };
+    let number = |part: &str, len: usize| -> Option<i64> {
+        if part.len() != len || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        part.parse().ok()
+    };
+    let day = number(day, 2)?;
+    let month = MONTHS.iter().position(|m| *m == month)? as i64 + 1;
+    let year = number(year, 4)?;
+    let (hour, minute, second) = match time.split(':').collect::<Vec<&str>>().as_slice() {
+        [h, m, s] => (number(h, 2)?, number(m, 2)?, number(s, 2)?),
+        _ => return None,
+    };
+    if !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let days = days_from_civil(year, month, day);
+    Some((days * 86_400 + hour * 3600 + minute * 60 + second) * 1000)
+}
+
+// inverse of `days_from_civil`
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = if days >= 0 { days } else { days - 146_096 } / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+// set the value at `parts`, creating intermediate objects as needed.
+// returns false when the path crosses a non-object value.
+fn set_value_in_doc(doc: &mut Value, parts: &[String], value: Value) -> bool {
+    let (last, parents) = match parts.split_last() {
+        Some(split) => split,
+        None => return false,
+    };
+    let mut current = doc;
+    for part in parents {
+        current = match current {
+            Value::Object(map) => map
+                .entry(part.clone())
+                .or_insert_with(|| Value::Object(serde_json::Map::new())),
+            _ => return false,
+        };
+    }
+    match current {
+        Value::Object(map) => {
+            map.insert(last.clone(), value);
+            true
+        }
+        _ => false,
+    }
+}
+
+// remove the value at `parts`, if there is one
+fn remove_value_in_doc(doc: &mut Value, parts: &[String]) {
+    let (last, parents) = match parts.split_last() {
+        Some(split) => split,
+        None => return,
+    };
+    let mut current = doc;
+    for part in parents {
+        current = match current.get_mut(part.as_str()) {
+            Some(value) => value,
+            None => return,
+        };
+    }
+    if let Value::Object(map) = current {
+        map.remove(last);
+    }
+}
+
+// the paths of a `$unset` array, split at the separator. None unless every entry is a
+// string without empty segments.
+fn unset_paths(paths: &Value, separator: char) -> Option<Vec<Vec<String>>> {
+    paths
+        .as_array()?
+        .iter()
+        .map(|path| {
+            let parts = split_path(path.as_str()?, separator);
+            Some(parts).filter(|parts| parts.iter().all(|p| !p.is_empty()))
+        })
+        .collect()
+}
+
+// JSON Merge Patch (RFC 7386): objects merge recursively, `null` removes a
+// key, and any other patch value replaces the target
+fn merge_patch(target: &mut Value, patch: &Value) {
+    let patch = match patch {
+        Value::Object(patch) => patch,
+        _ => {
+            *target = patch.clone();
+            return;
+        }
+    };
+    if !target.is_object() {
+        *target = Value::Object(serde_json::Map::new());
+    }
+    if let Value::Object(target) = target {
+        for (key, value) in patch {
+            if value.is_null() {
+                target.remove(key);
+            } else {
+                merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+    }
+}
+
+// the merge patch that turns `old` into `new`: changed keys with their new
+// value, nested objects as patches of their own, and removed keys as `null`.
+// a `null` in `new` reads as a removal too, as merge patches can't set one
+fn diff_documents(old: &Value, new: &Value) -> Value {
+    let (old, new) = match (old.as_object(), new.as_object()) {
+        (Some(old), Some(new)) => (old, new),
+        _ => return new.clone(),
+    };
+    let mut diff = serde_json::Map::new();
+    for (key, value) in new {
+        match old.get(key) {
+            Some(old_value) if old_value == value => {}
+            Some(old_value) if old_value.is_object() && value.is_object() => {
+                diff.insert(key.clone(), diff_documents(old_value, value));
+            }
+            _ => {
+                diff.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    for key in old.keys() {
+        if !new.contains_key(key) {
+            diff.insert(key.clone(), Value::Null);
+        }
+    }
+    Value::Object(diff)
+}
+
+// merge operator for the documents db. each operand is an `IncrRequest`
+// adding `by` to the numeric field at `field`. operands that target a
+// non-numeric field (or don't parse) leave the document unchanged.
+fn counter_full_merge(
+    _key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &MergeOperands,
+) -> Option<Vec<u8>> {
+    let mut doc = match existing {
+        Some(existing) => match serde_json::from_slice::<Value>(existing) {
+            Ok(doc) => doc,
+            // not a json document, nothing to count in
+            Err(_) => return Some(existing.to_vec()),
+        },
+        // the document was removed under us; keep only the counter
+        None => json!({}),
+    };
+    for operand in operands {
+        if let Ok(operand) = serde_json::from_slice::<Value>(operand) {
+            apply_incr(&mut doc, &operand);
+        }
+    }
+    serde_json::to_vec(&doc).ok()
+}
+
+// one operand of counter_full_merge
+fn apply_incr(doc: &mut Value, operand: &Value) {
+    let request = match IncrRequest::deserialize(operand) {
+        Ok(request) => request,
+        Err(_) => return,
+    };
+    // operands written before `path` existed only have the dotted field
+    let parts = match serde_json::from_value::<Vec<String>>(operand["path"].clone()) {
+        Ok(parts) => parts,
+        Err(_) => split_path(&request.field, '.'),
+    };
+    let current = match get_value_from_doc(doc.clone(), &parts) {
+        Value::Null => Number::from(0),
+        Value::Number(n) => n,
+        _ => return,
+    };
+    if let Some(value) = add_numbers(&current, &request.by) {
+        set_value_in_doc(doc, &parts, Value::Number(value));
+        if let (Some(doc), Some(at)) = (doc.as_object_mut(), operand.get("_updated_at")) {
+            doc.insert("_updated_at".to_string(), at.clone());
+        }
+    }
+}
+
+// operands are only combined against a base document
+fn counter_partial_merge(
+    _key: &[u8],
+    _existing: Option<&[u8]>,
+    _operands: &MergeOperands,
+) -> Option<Vec<u8>> {
+    None
+}
+
+// integer addition stays exact; anything involving a float is done in f64
+fn add_numbers(a: &Number, b: &Number) -> Option<Number> {
+    if let (Some(a), Some(b)) = (a.as_i64(), b.as_i64()) {
+        return a.checked_add(b).map(Number::from);
+    }
+    Number::from_f64(a.as_f64()? + b.as_f64()?)
+}
+
+fn get_path_values(document: &Value, path: String) -> Vec<String> {
+    // each path_values is a string of the form "path:value"
+    let mut path_values = Vec::new();
+    let doc = document.as_object().unwrap();
+    for (key, value) in doc.into_iter() {
+        match value {
+            Value::Object(inner_map) => {
+                let new_path = format!("{}.{}", path, key);
+                path_values.extend(get_path_values(&json!(inner_map), new_path));
+            }
+            // `=` matches an array holding the value, so every distinct scalar
+            // element gets its key; nested arrays and objects aren't indexed
+            Value::Array(items) => {
+                let key = format!("{}.{}", path, key);
+                let elements = items
+                    .iter()
+                    .filter(|item| !item.is_array() && !item.is_object())
+                    .map(|item| format!("{}:{}", key, item))
+                    .collect::<BTreeSet<String>>();
+                path_values.extend(elements);
+            }
+            _ => {
+                let key = format!("{}.{}", path, key);
+                path_values.push(format!("{}:{}", key, value));
+            }
+        }
+    }
+
+    path_values
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchRequest {
+    #[serde(default)]
+    q: String,
+    // candidate documents; without it every document is searched
+    ids: Option<Vec<String>>,
+    limit: Option<usize>,
+    #[serde(default)]
+    offset: usize,
+    #[serde(default)]
+    ci_keys: bool,
+    #[serde(default)]
+    include_deleted: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct MgetRequest {
+    ids: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImportRequest {
+    // a file under one of IMPORT_DIRS, one json document per line
+    path: String,
+}
+
+// documents written with one WriteBatch by POST /admin/import-file
+const IMPORT_BATCH_SIZE: usize = 1000;
+// lines reported in the response; later errors are only counted
+const MAX_IMPORT_ERRORS: usize = 100;
+
+// documents between two progress logs of --reindex-on-start
+const REINDEX_LOG_INTERVAL: usize = 100_000;
+
+#[derive(Debug, Deserialize)]
+struct IncrRequest {
+    field: String,
+    #[serde(default = "IncrRequest::default_by")]
+    by: Number,
+}
+
+impl IncrRequest {
+    fn default_by() -> Number {
+        Number::from(1)
+    }
+}
+
+#[derive(Debug)]
+struct QueryCondition {
+    key: String,
+    value: String,
+    op: String,
+    // `!key:op value` inverts the result of the condition
+    negate: bool,
+    // the conditions inside `key:elemMatch{...}`
+    sub_query: Option<Query>,
+    // the parsed literal of `key:={...}` or `key:=[...]`
+    json_value: Option<Value>,
+    // the compiled pattern of `key:like"..."`
+    pattern: Option<regex::Regex>,
+    // how `=`, `>` and `<` read both sides, from a following `as:number` or `as:date`
+    compare_as: CompareAs,
+    // `key:>field:other`: the right-hand side is the value at this path instead
+    rhs_field: Option<String>,
+    // `key:all>value`: every element of an array has to satisfy `>` or `<`,
+    // instead of any one
+    all: bool,
+    // `status:active,pending`: `=` holds for any of these; empty for one value
+    any_of: Vec<String>,
+    // `location:near(lat,lon,radius_km)`: the center in degrees and the radius in km
+    near: Option<(f64, f64, f64)>,
+    // `key:%(divisor,remainder)`
+    modulo: Option<(i64, i64)>,
+    // `key:~=(target,tolerance)`
+    approx: Option<(f64, f64)>,
+    // `tags:count("a"):>1`: the element whose occurrences are compared
+    count_of: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CompareAs {
+    // numbers, or dates when both sides are dates
+    Auto,
+    Number,
+    Date,
+}
+
+impl CompareAs {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "number" => Some(CompareAs::Number),
+            "date" => Some(CompareAs::Date),
+            _ => None,
+        }
+    }
+
+    fn compare(self, value: &Value, rhs: &str) -> Option<Ordering> {
+        match self {
+            CompareAs::Auto => compare_numbers(value, rhs).or_else(|| compare_dates(value, rhs)),
+            CompareAs::Number => compare_numbers(value, rhs),
+            CompareAs::Date => compare_dates(value, rhs),
+        }
+    }
+}
+
+impl QueryCondition {
+    fn new(key: String, value: String, op: String) -> Self {
+        Self {
+            key,
+            value,
+            op,
+            negate: false,
+            sub_query: None,
+            json_value: None,
+            pattern: None,
+            compare_as: CompareAs::Auto,
+            rhs_field: None,
+            all: false,
+            any_of: vec![],
+            near: None,
+            modulo: None,
+            approx: None,
+            count_of: None,
+        }
+    }
+
+    // the values `=` compares with, one unless the query listed several
+    fn values(&self) -> &[String] {
+        if self.any_of.is_empty() {
+            std::slice::from_ref(&self.value)
+        } else {
+            &self.any_of
+        }
+    }
+}
+
+impl QueryCondition {
+    // the posting lists holding every document that can satisfy a plain `=`.
+    // numbers compare numerically, so `1999` also equals a stored `1999.0` and
+    // only strings and booleans are looked up
+    fn index_keys(&self, options: PathOptions) -> Option<Vec<String>> {
+        let plain = self.op == "="
+            && !self.negate
+            && self.compare_as == CompareAs::Auto
+            && self.rhs_field.is_none()
+            && self.json_value.is_none();
+        let numeric = self.values().iter().any(|v| v.parse::<f64>().is_ok());
+        let parts = split_path(&self.key, options.separator);
+        if !plain || numeric || parts.len() > options.max_depth {
+            return None;
+        }
+        // the same `.path:json` keys get_path_values writes, which always joins
+        // with dots; a key containing dots only finds more candidates
+        let path = format!(".{}", parts.join("."));
+        let mut keys = Vec::new();
+        for value in self.values() {
+            keys.push(format!("{}:{}", path, Value::String(value.clone())));
+            if let Ok(b) = value.parse::<bool>() {
+                keys.push(format!("{}:{}", path, b));
+            }
+        }
+        Some(keys)
+    }
+}
+
+// translate a `like` pattern into an anchored regex: `%` matches any
+// sequence, `_` any single character and everything else itself
+fn like_to_regex(pattern: &str) -> Result<regex::Regex, regex::Error> {
+    let mut re = String::from("(?s)^");
+    for c in pattern.chars() {
+        match c {
+            '%' => re.push_str(".*"),
+            '_' => re.push('.'),
+            c => re.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    re.push('$');
+    regex::Regex::new(&re)
+}
+
+#[derive(Debug)]
 struct Query {
     conditions: Vec<QueryCondition>,
+    // tolerance of `=` between two floats
+    epsilon: f64,
+    // `?modified_since=`: only documents updated after this, in unix millis
+    modified_since: Option<i64>,
+    // `?created_from=` and `?created_to=`: bounds of `_created_at`, both inclusive
+    created_from: Option<i64>,
+    created_to: Option<i64>,
+    path_options: PathOptions,
+    // `?include_deleted=true`: tombstones match like any other document
+    include_deleted: bool,
+}
+
+const DEFAULT_FLOAT_EPSILON: f64 = 1e-6;
+
+impl Query {
+    // the same string for queries that only differ in spacing, quoting or the
+    // order of their conditions
+    fn normalized(&self) -> String {
+        let mut conditions = self
+            .conditions
+            .iter()
+            .map(|c| {
+                let negate = if c.negate { "!" } else { "" };
+                let op = if c.all {
+                    format!("all{}", c.op)
+                } else {
+                    c.op.clone()
+                };
+                match &c.sub_query {
+                    Some(sub) => format!("{}{:?}:{}{{{}}}", negate, c.key, op, sub.normalized()),
+                    None if c.rhs_field.is_some() => format!(
+                        "{}{:?}:{}field:{:?}as:{:?}",
+                        negate, c.key, op, c.rhs_field, c.compare_as
+                    ),
+                    None if c.count_of.is_some() => {
+                        format!("{}{:?}:{}{:?}{:?}", negate, c.key, op, c.count_of, c.value)
+                    }
+                    // `tags:=[1]` isn't the string `tags:="[1]"`
+                    None if c.json_value.is_some() => {
+                        let value = c.json_value.as_ref().unwrap();
+                        format!("{}{:?}:{}json:{}", negate, c.key, op, value)
+                    }
+                    None if !c.any_of.is_empty() => format!(
+                        "{}{:?}:{}{:?}as:{:?}",
+                        negate, c.key, op, c.any_of, c.compare_as
+                    ),
+                    None if c.compare_as != CompareAs::Auto => format!(
+                        "{}{:?}:{}{:?}as:{:?}",
+                        negate, c.key, op, c.value, c.compare_as
+                    ),
+                    None => format!("{}{:?}:{}{:?}", negate, c.key, op, c.value),
+                }
+            })
+            .collect::<Vec<String>>();
+        if let Some(since) = self.modified_since {
+            conditions.push(format!("modified_since:{}", since));
+        }
+        if let Some(from) = self.created_from {
+            conditions.push(format!("created_from:{}", from));
+        }
+        if let Some(to) = self.created_to {
+            conditions.push(format!("created_to:{}", to));
+        }
+        if self.path_options.case_insensitive {
+            conditions.push("ci_keys".to_string());
+        }
+        if self.include_deleted {
+            conditions.push("include_deleted".to_string());
+        }
+        conditions.sort();
+        conditions.join(" ")
+    }
+
+    fn set_epsilon(&mut self, epsilon: f64) {
+        self.epsilon = epsilon;
+        for condition in &mut self.conditions {
+            if let Some(sub) = &mut condition.sub_query {
+                sub.set_epsilon(epsilon);
+            }
+        }
+    }
+
+    fn set_path_options(&mut self, options: PathOptions) {
+        self.path_options = options;
+        for condition in &mut self.conditions {
+            if let Some(sub) = &mut condition.sub_query {
+                sub.set_path_options(options);
+            }
+        }
+    }
+
+    // the posting-list keys of each condition the index can answer. every
+    // match is in one list of each entry; empty means a full scan
+    fn index_lookups(&self) -> Vec<Vec<String>> {
+        // keys in another case and tombstones aren't in the posting lists
+        if self.path_options.case_insensitive || self.include_deleted {
+            return vec![];
+        }
+        self.conditions
+            .iter()
+            .filter_map(|condition| condition.index_keys(self.path_options))
+            .collect()
+    }
+
+    fn matches(&self, doc: &Value) -> bool {
+        if is_tombstone(doc) && !self.include_deleted {
+            return false;
+        }
+        self.matches_at(doc, 0)
+    }
+
+    // `depth` is how far into the document an enclosing elemMatch already is;
+    // conditions reaching past the max path depth don't match
+    fn matches_at(&self, doc: &Value, depth: usize) -> bool {
+        if let Some(since) = self.modified_since {
+            let modified = doc
+                .get("_updated_at")
+                .or_else(|| doc.get("_created_at"))
+                .and_then(|v| v.as_str())
+                .and_then(parse_rfc3339);
+            match modified {
+                Some(modified) if modified > since => {}
+                // documents stored before the timestamps existed are never newer
+                _ => return false,
+            }
+        }
+        if self.created_from.is_some() || self.created_to.is_some() {
+            let created = doc
+                .get("_created_at")
+                .and_then(|v| v.as_str())
+                .and_then(parse_rfc3339);
+            let in_range = created.is_some_and(|created| {
+                self.created_from.is_none_or(|from| created >= from)
+                    && self.created_to.is_none_or(|to| created <= to)
+            });
+            if !in_range {
+                return false;
+            }
+        }
+        let resolve = |path: &str| {
+            let parts = split_path(path, self.path_options.separator);
+            if depth + parts.len() > self.path_options.max_depth {
+                Value::Null
+            } else {
+                get_value_with_options(doc.clone(), &parts, self.path_options)
+            }
+        };
+        for condition in &self.conditions {
+            let value = resolve(&condition.key);
+            let depth = depth + condition.key.split(self.path_options.separator).count();
+            let rhs = match &condition.rhs_field {
+                Some(path) => scalar_operand(&resolve(path)).map(Cow::Owned),
+                None => Some(Cow::Borrowed(condition.value.as_str())),
+            };
+            // a missing field, on either side, fails the condition (and so
+            // passes its negation)
+            let missing = value.is_null() || rhs.is_none();
+            let rhs = rhs.as_deref().unwrap_or_default();
+            // `=` holds for an array when any element is equal, so
+            // `!tags:=archived` keeps the documents not tagged `archived`
+            let equals = |rhs: &str| {
+                let holds = |v: &Value| match condition.compare_as {
+                    CompareAs::Auto => values_equal(v, rhs, self.epsilon),
+                    compare_as => compare_as.compare(v, rhs) == Some(Ordering::Equal),
+                };
+                match &value {
+                    Value::Array(items) => items.iter().any(holds),
+                    value => holds(value),
+                }
+            };
+            // `>` and `<` hold for an array when any element satisfies them, or
+            // with `all` when every one does
+            let compares = |ordering: Ordering| {
+                let holds = |v: &Value| condition.compare_as.compare(v, rhs) == Some(ordering);
+                match &value {
+                    Value::Array(items) if condition.all => {
+                        !items.is_empty() && items.iter().all(holds)
+                    }
+                    Value::Array(items) => items.iter().any(holds),
+                    value => holds(value),
+                }
+            };
+            let matches = !missing
+                && match condition.op.as_str() {
+                    "exists" => true,
+                    // deep equality; object keys compare regardless of their order
+                    "=" if condition.json_value.is_some() => {
+                        condition.json_value.as_ref() == Some(&value)
+                    }
+                    "=" if condition.rhs_field.is_some() => equals(rhs),
+                    "=" => condition.values().iter().any(|v| equals(v)),
+                    // each bound is checked on its own, so `price:>10 price:<100`
+                    // expresses a range on one field
+                    ">" => compares(Ordering::Greater),
+                    "<" => compares(Ordering::Less),
+                    // one element has to satisfy every condition of the sub-query
+                    "elemMatch" => match (&value, &condition.sub_query) {
+                        (Value::Array(items), Some(sub)) => {
+                            items.iter().any(|item| sub.matches_at(item, depth))
+                        }
+                        _ => false,
+                    },
+                    // only strings can match a pattern
+                    "like" => match (&value, &condition.pattern) {
+                        (Value::String(s), Some(pattern)) => pattern.is_match(s),
+                        _ => false,
+                    },
+                    // only `{"lat": .., "lon": ..}` objects are points
+                    "near" => match (geo_point(&value), condition.near) {
+                        (Some((lat, lon)), Some((center_lat, center_lon, radius_km))) => {
+                            haversine_km(lat, lon, center_lat, center_lon) <= radius_km
+                        }
+                        _ => false,
+                    },
+                    // elements count when they equal the value like with `=`
+                    "count=" | "count>" | "count<" => match (&value, &condition.count_of) {
+                        (Value::Array(items), Some(item)) => {
+                            let count = items
+                                .iter()
+                                .filter(|v| values_equal(v, item, self.epsilon))
+                                .count();
+                            let expected = match condition.op.as_str() {
+                                "count>" => Ordering::Greater,
+                                "count<" => Ordering::Less,
+                                _ => Ordering::Equal,
+                            };
+                            compare_numbers(&Value::from(count), rhs) == Some(expected)
+                        }
+                        _ => false,
+                    },
+                    // only integers have a remainder; a float like 10.0 doesn't
+                    "%" => match (value.as_i64(), condition.modulo) {
+                        (Some(n), Some((divisor, remainder))) => n.rem_euclid(divisor) == remainder,
+                        _ => false,
+                    },
+                    // numbers only, like `near`; numeric strings don't match
+                    "~=" => match (value.as_f64(), condition.approx) {
+                        (Some(n), Some((target, tolerance))) => (n - target).abs() <= tolerance,
+                        _ => false,
+                    },
+                    _ => panic!("Invalid operator"),
+                };
+            if matches == condition.negate {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// the latitude and longitude of a `{"lat": .., "lon": ..}` object, in degrees
+fn geo_point(value: &Value) -> Option<(f64, f64)> {
+    let lat = value.get("lat")?.as_f64()?;
+    let lon = value.get("lon")?.as_f64()?;
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+        return None;
+    }
+    Some((lat, lon))
+}
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+// great-circle distance of two points given in degrees
+fn haversine_km(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lon = (lon2 - lon1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().min(1.0).asin()
+}
+
+// equality of a document value with a query value, which is always a
+// string: strings compare as-is, numbers numerically and booleans by name.
+// two floats are equal within `epsilon`, integers only when exactly equal.
+fn values_equal(value: &Value, rhs: &str, epsilon: f64) -> bool {
+    match value {
+        Value::String(s) => s == rhs,
+        Value::Number(n) if n.is_f64() && rhs.parse::<i64>().is_err() => {
+            match (n.as_f64(), rhs.parse::<f64>()) {
+                (Some(lhs), Ok(rhs)) => (lhs - rhs).abs() <= epsilon,
+                _ => false,
+            }
+        }
+        Value::Number(_) => compare_numbers(value, rhs) == Some(Ordering::Equal),
+        Value::Bool(b) => rhs.parse::<bool>() == Ok(*b),
+        // null, arrays and objects never equal a scalar
+        _ => false,
+    }
+}
+
+// compare a document value with a query value as numbers. numeric strings
+// count as numbers; anything else (or a non-numeric query value) is None.
+// two integers compare exactly, beyond the 2^53 an f64 holds without loss.
+fn compare_numbers(value: &Value, rhs: &str) -> Option<Ordering> {
+    let lhs_integer = match value {
+        Value::Number(n) => n
+            .as_i64()
+            .map(i128::from)
+            .or_else(|| n.as_u64().map(i128::from)),
+        Value::String(s) => s.trim().parse::<i128>().ok(),
+        _ => None,
+    };
+    if let (Some(lhs), Ok(rhs)) = (lhs_integer, rhs.parse::<i128>()) {
+        return Some(lhs.cmp(&rhs));
+    }
+    let lhs = match value {
+        Value::Number(n) => n.as_f64()?,
+        Value::String(s) => s.trim().parse::<f64>().ok()?,
+        _ => return None,
+    };
+    let rhs = rhs.parse::<f64>().ok()?;
+    lhs.partial_cmp(&rhs)
+}
+
+// a field on the right-hand side as a query value; only scalars compare
+fn scalar_operand(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+// compare a document string with a query value as points in time. both have
+// to be an RFC 3339 timestamp or a `YYYY-MM-DD` date, which means midnight UTC
+fn compare_dates(value: &Value, rhs: &str) -> Option<Ordering> {
+    let lhs = parse_date(value.as_str()?)?;
+    let rhs = parse_date(rhs)?;
+    Some(lhs.cmp(&rhs))
+}
+
+fn parse_date(s: &str) -> Option<i64> {
+    if s.len() == 10 {
+        return parse_rfc3339(&format!("{}T00:00:00Z", s));
+    }
+    parse_rfc3339(s)
+}
+
+// total order for sorting json values: booleans, then numbers, strings,
+// arrays and objects; values of the same kind compare naturally
+fn compare_values(a: &Value, b: &Value) -> Ordering {
+    fn rank(value: &Value) -> u8 {
+        match value {
+            Value::Null => 0,
+            Value::Bool(_) => 1,
+            Value::Number(_) => 2,
+            Value::String(_) => 3,
+            Value::Array(_) => 4,
+            Value::Object(_) => 5,
+        }
+    }
+    match (a, b) {
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Number(a), Value::Number(b)) => {
+            let (a, b) = (a.as_f64().unwrap_or(0.0), b.as_f64().unwrap_or(0.0));
+            a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+        }
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        _ if rank(a) == rank(b) => a.to_string().cmp(&b.to_string()),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+const DEFAULT_MAX_VALUE_LENGTH: usize = 4096;
+
+// the value of a `@name` in a query, taken when the query is parsed, so every
+// document of a search sees the same instant
+fn query_constant(name: &str) -> Option<String> {
+    let now = now_millis();
+    match name {
+        "now" => Some(format_rfc3339(now)),
+        // midnight utc of the current day
+        "today" => Some(format_rfc3339(now - now.rem_euclid(86_400_000))),
+        _ => None,
+    }
+}
+
+fn lex_string(input: &str, max_len: usize) -> Result<(&str, &str), &str> {
+    let input = input.trim_start();
+    let (s, remaining) = if input.starts_with('"') {
+        let end = input[1..]
+            .find('"')
+            .ok_or("Expected end of quoted string")?
+            + 1;
+        (&input[1..end], &input[end + 1..])
+    } else {
+        let end = input
+            .find(|c: char| !c.is_alphanumeric() && c != '.')
+            .unwrap_or_else(|| input.len());
+        if end == 0 {
+            return Err("No string found");
+        }
+        (&input[..end], &input[end..])
+    };
+    if s.len() > max_len {
+        return Err("Query value is too long");
+    }
+    Ok((s, remaining))
+}
+
+// position of the `}` closing a `{` that was just before `s`
+fn closing_brace(s: &str) -> Option<usize> {
+    let mut depth = 1;
+    let mut quoted = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '{' if !quoted => depth += 1,
+            '}' if !quoted => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn parse_query(q: &str, max_len: usize) -> Result<Query, &str> {
+    let mut query = q.trim_start();
+    let mut parsed = Query {
+        conditions: vec![],
+        epsilon: DEFAULT_FLOAT_EPSILON,
+        modified_since: None,
+        created_from: None,
+        created_to: None,
+        path_options: PathOptions::default(),
+        include_deleted: false,
+    };
+
+    while !query.is_empty() {
+        let negate = query.starts_with('!');
+        if negate {
+            query = query[1..].trim_start();
+        }
+
+        let (key, remaining) = lex_string(query, max_len)?;
+        query = remaining.trim_start();
+
+        // a bare key without `:` only requires the field to be present
+        if !query.starts_with(':') {
+            let key = key.to_owned();
+            let mut argument = QueryCondition::new(key, "".to_string(), "exists".to_string());
+            argument.negate = negate;
+            parsed.conditions.push(argument);
+            continue;
+        }
+        query = query[1..].trim_start();
+
+        if let Some(rest) = query.strip_prefix("elemMatch{") {
+            let end = closing_brace(rest).ok_or("Expected end of elemMatch")?;
+            let sub_query = parse_query(&rest[..end], max_len)?;
+            query = rest[end + 1..].trim_start();
+            let mut argument =
+                QueryCondition::new(key.to_owned(), "".to_string(), "elemMatch".to_string());
+            argument.negate = negate;
+            argument.sub_query = Some(sub_query);
+            parsed.conditions.push(argument);
+            continue;
+        }
+
+        // `key:like"rust%db"`; `key:likeable` is still an equality
+        if let Some(rest) = query
+            .strip_prefix("like")
+            .filter(|rest| rest.starts_with('"') || rest.starts_with(char::is_whitespace))
+        {
+            let (value, remaining) = lex_string(rest, max_len)?;
+            query = remaining.trim_start();
+            let pattern = like_to_regex(value).map_err(|_| "Invalid like pattern")?;
+            let mut argument =
+                QueryCondition::new(key.to_owned(), value.to_owned(), "like".to_string());
+            argument.negate = negate;
+            argument.pattern = Some(pattern);
+            parsed.conditions.push(argument);
+            continue;
+        }
+
+        // `location:near(52.52,13.40,5)`; `key:nearby` is still an equality
+        if let Some(rest) = query.strip_prefix("near(") {
+            let end = rest.find(')').ok_or("Expected end of near")?;
+            if end > max_len {
+                return Err("Query value is too long");
+            }
+            let numbers = rest[..end]
+                .split(',')
+                .map(|n| n.trim().parse::<f64>())
+                .collect::<Result<Vec<f64>, _>>()
+                .map_err(|_| "Expected numbers in near(lat,lon,radius_km)")?;
+            let (lat, lon, radius_km) = match numbers[..] {
+                [lat, lon, radius_km]
+                    if (-90.0..=90.0).contains(&lat)
+                        && (-180.0..=180.0).contains(&lon)
+                        && radius_km.is_finite()
+                        && radius_km >= 0.0 =>
+                {
+                    (lat, lon, radius_km)
+                }
+                _ => return Err("Invalid point or radius in near(lat,lon,radius_km)"),
+            };
+            query = rest[end + 1..].trim_start();
+            let value = format!("{},{},{}", lat, lon, radius_km);
+            let mut argument = QueryCondition::new(key.to_owned(), value, "near".to_string());
+            argument.negate = negate;
+            argument.near = Some((lat, lon, radius_km));
+            parsed.conditions.push(argument);
+            continue;
+        }
+
+        // `tags:count("a"):>1` compares how often an array holds "a"
+        if let Some(rest) = query.strip_prefix("count(") {
+            let (item, remaining) = lex_string(rest, max_len)?;
+            let remaining = remaining
+                .trim_start()
+                .strip_prefix(')')
+                .ok_or("Expected end of count")?;
+            let remaining = remaining
+                .strip_prefix(':')
+                .ok_or("Expected : after count(...)")?;
+            let op = match remaining.chars().next() {
+                Some(c @ ('>' | '<' | '=')) => format!("count{}", c),
+                _ => return Err("Expected >, < or = after count(...):"),
+            };
+            let (value, remaining) = lex_string(&remaining[1..], max_len)?;
+            if value.parse::<f64>().is_err() {
+                return Err("count(...) compares with a number");
+            }
+            query = remaining.trim_start();
+            let mut argument = QueryCondition::new(key.to_owned(), value.to_owned(), op);
+            argument.negate = negate;
+            argument.count_of = Some(item.to_owned());
+            parsed.conditions.push(argument);
+            continue;
+        }
+
+        // `id_num:%(10,0)` keeps every tenth number
+        if let Some(rest) = query.strip_prefix("%(") {
+            let end = rest.find(')').ok_or("Expected end of %")?;
+            if end > max_len {
+                return Err("Query value is too long");
+            }
+            let numbers = rest[..end]
+                .split(',')
+                .map(|n| n.trim().parse::<i64>())
+                .collect::<Result<Vec<i64>, _>>()
+                .map_err(|_| "Expected integers in %(divisor,remainder)")?;
+            let (divisor, remainder) = match numbers[..] {
+                [0, _] => return Err("The divisor of % must not be zero"),
+                [divisor, remainder] if divisor > 0 && (0..divisor).contains(&remainder) => {
+                    (divisor, remainder)
+                }
+                _ => return Err("% needs a positive divisor and a remainder below it"),
+            };
+            query = rest[end + 1..].trim_start();
+            let value = format!("{},{}", divisor, remainder);
+            let mut argument = QueryCondition::new(key.to_owned(), value, "%".to_string());
+            argument.negate = negate;
+            argument.modulo = Some((divisor, remainder));
+            parsed.conditions.push(argument);
+            continue;
+        }
+
+        // `temp:~=(20,0.5)` is 19.5 to 20.5
+        if let Some(rest) = query.strip_prefix("~=(") {
+            let end = rest.find(')').ok_or("Expected end of ~=")?;
+            if end > max_len {
+                return Err("Query value is too long");
+            }
+            let numbers = rest[..end]
+                .split(',')
+                .map(|n| n.trim().parse::<f64>())
+                .collect::<Result<Vec<f64>, _>>()
+                .map_err(|_| "Expected numbers in ~=(target,tolerance)")?;
+            let (target, tolerance) = match numbers[..] {
+                [target, tolerance] if target.is_finite() && tolerance.is_finite() => {
+                    (target, tolerance)
+                }
+                _ => return Err("~= needs a target and a tolerance"),
+            };
+            if tolerance < 0.0 {
+                return Err("The tolerance of ~= must not be negative");
+            }
+            query = rest[end + 1..].trim_start();
+            let value = format!("{},{}", target, tolerance);
+            let mut argument = QueryCondition::new(key.to_owned(), value, "~=".to_string());
+            argument.negate = negate;
+            argument.approx = Some((target, tolerance));
+            parsed.conditions.push(argument);
+            continue;
+        }
+
+        // `temps:all>25`; `key:allx` is still an equality
+        let all = query.starts_with("all>") || query.starts_with("all<");
+        if all {
+            query = &query[3..];
+        }
+        let explicit_op = matches!(query.chars().next(), Some('>') | Some('<') | Some('='));
+        let op = match query.chars().next() {
+            Some('>') | Some('<') | Some('=') => {
+                let op = query[0..1].to_string();
+                query = query[1..].trim_start();
+                op
+            }
+            _ => "=".to_string(),
+        };
+
+        // `key:={...}` / `key:=[...]` compares with a json literal as a whole
+        if op == "=" && (query.starts_with('{') || query.starts_with('[')) {
+            let mut values = serde_json::Deserializer::from_str(query).into_iter::<Value>();
+            let json_value = match values.next() {
+                Some(Ok(value)) => value,
+                _ => return Err("Invalid JSON literal"),
+            };
+            let (literal, remaining) = query.split_at(values.byte_offset());
+            if literal.len() > max_len {
+                return Err("Query value is too long");
+            }
+            query = remaining.trim_start();
+            let mut argument = QueryCondition::new(key.to_owned(), literal.to_owned(), op);
+            argument.negate = negate;
+            argument.json_value = Some(json_value);
+            parsed.conditions.push(argument);
+            continue;
+        }
+
+        // `spent:>field:budget` compares two fields of the same document
+        if let Some(rest) = query.strip_prefix("field:") {
+            let (path, remaining) = lex_string(rest, max_len)?;
+            query = remaining.trim_start();
+            let mut argument = QueryCondition::new(key.to_owned(), "".to_string(), op);
+            argument.negate = negate;
+            argument.rhs_field = Some(path.to_owned());
+            argument.all = all;
+            parsed.conditions.push(argument);
+            continue;
+        }
+
+        // `expires:>@now`; a quoted "@now" is the literal string
+        let (value, remaining) = match query.strip_prefix('@') {
+            Some(rest) => {
+                let end = rest
+                    .find(|c: char| !c.is_alphanumeric())
+                    .unwrap_or(rest.len());
+                let value = query_constant(&rest[..end])
+                    .ok_or("Unknown constant, expected @now or @today")?;
+                (Cow::Owned(value), &rest[end..])
+            }
+            None => {
+                let (value, remaining) = lex_string(query, max_len)?;
+                (Cow::Borrowed(value), remaining)
+            }
+        };
+        let value: &str = &value;
+        query = remaining;
+        // `status:active,pending` matches either; a quoted value keeps its commas
+        let mut any_of = vec![];
+        while let Some(rest) = query.strip_prefix(',') {
+            let (next, remaining) = lex_string(rest, max_len)?;
+            if any_of.is_empty() {
+                any_of.push(value.to_owned());
+            }
+            any_of.push(next.to_owned());
+            query = remaining;
+        }
+        query = query.trim_start();
+        if !any_of.is_empty() && op != "=" {
+            return Err("A list of values needs =");
+        }
+
+        // `as:number` or `as:date` is a hint for the condition before it
+        if key == "as" && !negate && !explicit_op && any_of.is_empty() {
+            if let Some(compare_as) = CompareAs::from_name(value) {
+                match parsed.conditions.last_mut() {
+                    Some(last) if matches!(last.op.as_str(), "=" | ">" | "<") => {
+                        if last.json_value.is_some() {
+                            return Err("as: cannot compare a JSON literal");
+                        }
+                        last.compare_as = compare_as;
+                        continue;
+                    }
+                    _ => return Err("as: must follow a =, > or < condition"),
+                }
+            }
+        }
+
+        let key = key.to_owned();
+        let value = if any_of.is_empty() {
+            value.to_owned()
+        } else {
+            any_of.join(",")
+        };
+        let mut argument = QueryCondition::new(key, value, op);
+        argument.negate = negate;
+        argument.all = all;
+        argument.any_of = any_of;
+        parsed.conditions.push(argument);
+    }
+
+    Ok(parsed)
+}
+
+// the conditions of all `queries`; each is parsed on its own, so a quote
+// can't run from one into the next
+fn parse_queries(queries: &[String], max_len: usize) -> Result<Query, &str> {
+    let mut parsed = parse_query("", max_len)?;
+    for q in queries {
+        parsed
+            .conditions
+            .extend(parse_query(q, max_len)?.conditions);
+    }
+    Ok(parsed)
+}
+
+fn main() {
+    let config = Config::load();
+    EXPOSE_INTERNAL_ERRORS.store(config.expose_internal_errors, AtomicOrdering::Relaxed);
+    match config.log_format {
+        LogFormat::Json => tracing_subscriber::fmt().json().init(),
+        LogFormat::Pretty => tracing_subscriber::fmt().pretty().init(),
+    }
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(worker_threads) = config.worker_threads {
+        runtime.worker_threads(worker_threads);
+    }
+    let runtime = runtime.build().unwrap();
+    runtime.block_on(serve(config));
 }
 
-impl Query {
-    fn matches(&self, doc: &Value) -> bool {
-        for condition in &self.conditions {
-            let value = get_value_from_doc(
-                doc.clone(),
-                &condition
-                    .key
-                    .split(".")
-                    .map(|s| s.to_string())
-                    .collect::<Vec<String>>(),
-            );
-            if value.is_null() {
-                return false;
-            }
-            let matches = match condition.op.as_str() {
-                "=" => {
-                    let lhs = value.to_string();
-                    let rhs = condition.value.clone();
-                    lhs == rhs
-                }
-                // only supports int comparison
-                ">" => {
-                    let lhs = value.to_string().trim_matches('\"').parse::<i32>().unwrap();
-                    let rhs = condition.value.parse::<i32>().unwrap();
-                    lhs > rhs
-                }
-                "<" => {
-                    let lhs = value.to_string().trim_matches('\"').parse::<i32>().unwrap();
-                    let rhs = condition.value.parse::<i32>().unwrap();
-                    lhs < rhs
-                }
-                _ => panic!("Invalid operator"),
-            };
-            if !matches {
-                return false;
-            }
-        }
-        true
-    }
-}
+async fn serve(config: Config) {
+    let server = match Server::new("docdb.data", config) {
+        Ok(server) => Arc::new(server),
+        Err(e) => panic!("failed to open the databases: {}", e),
+    };
+    // a read-only server can't write the index, so it keeps the one it has
+    if server.config.reindex_on_start && !server.config.read_only {
+        if let Err(e) = server.reindex() {
+            panic!("failed to rebuild the index: {}", e);
+        }
+    }
+
+    // rejects write routes when the server runs with --read-only
+    let writable = {
+        let read_only = server.config.read_only;
+        warp::any()
+            .and_then(move || async move {
+                if read_only {
+                    Err(warp::reject::custom(ReadOnlyMode))
+                } else {
+                    Ok(())
+                }
+            })
+            .untuple_one()
+    };
+
+    // per-ip token bucket in front of every route but the health checks
+    let rate_limit = {
+        let server_clone = Arc::clone(&server);
+        warp::addr::remote()
+            .and(warp::header::optional::<String>("x-forwarded-for"))
+            .and_then(
+                move |remote: Option<SocketAddr>, forwarded_for: Option<String>| {
+                    let server = Arc::clone(&server_clone);
+                    async move {
+                        // the first address is the client, the rest are proxies
+                        let forwarded = forwarded_for
+                            .filter(|_| server.config.trust_forwarded_for)
+                            .and_then(|v| v.split(',').next()?.trim().parse::<IpAddr>().ok());
+                        let ip = match forwarded.or(remote.map(|addr| addr.ip())) {
+                            Some(ip) => ip,
+                            None => return Ok(()),
+                        };
+                        server.take_token(ip).map_err(|retry_after| {
+                            warp::reject::custom(RateLimited { retry_after })
+                        })
+                    }
+                },
+            )
+            .untuple_one()
+    };
+
+    // guards the /admin routes with the configured api key
+    let admin = {
+        let api_key = server.config.admin_api_key.clone();
+        warp::header::optional::<String>("x-api-key")
+            .and_then(move |given: Option<String>| {
+                let api_key = api_key.clone();
+                async move {
+                    match (api_key, given) {
+                        (None, _) => Err(warp::reject::custom(AdminDisabled)),
+                        (Some(key), Some(given)) if key == given => Ok(()),
+                        _ => Err(warp::reject::custom(Unauthorized)),
+                    }
+                }
+            })
+            .untuple_one()
+    };
+
+    let add_document = {
+        let server_clone = Arc::clone(&server);
+        warp::post()
+            .and(warp::path("docs"))
+            .and(warp::path::end())
+            .and(writable)
+            .and(warp::body::json())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(warp::any().map(move || Arc::clone(&server_clone)))
+            .and_then(
+                |document, query: HashMap<String, String>, server: Arc<Server>| {
+                    server.add_document(document, query.get("return").cloned())
+                },
+            )
+    };
+
+    let delete_documents = {
+        let server_clone = Arc::clone(&server);
+        warp::post()
+            .and(warp::path("docs"))
+            .and(warp::path("delete"))
+            .and(warp::path::end())
+            .and(writable)
+            .and(warp::body::json())
+            .and(warp::any().map(move || Arc::clone(&server_clone)))
+            .and_then(|ids, server: Arc<Server>| server.delete_documents(ids))
+    };
+
+    let incr_document = {
+        let server_clone = Arc::clone(&server);
+        warp::post()
+            .and(warp::path("docs"))
+            .and(doc_id())
+            .and(warp::path("incr"))
+            .and(warp::path::end())
+            .and(writable)
+            .and(warp::body::json())
+            .and(warp::any().map(move || Arc::clone(&server_clone)))
+            .and_then(|id, request, server: Arc<Server>| server.incr_document(id, request))
+    };
+
+    let restore_document = {
+        let server_clone = Arc::clone(&server);
+        warp::post()
+            .and(warp::path("docs"))
+            .and(doc_id())
+            .and(warp::path("restore"))
+            .and(warp::path::param::<u64>())
+            .and(warp::path::end())
+            .and(writable)
+            .and(warp::any().map(move || Arc::clone(&server_clone)))
+            .and_then(|id, version, server: Arc<Server>| server.restore_document(id, version))
+    };
+
+    let patch_document = {
+        let server_clone = Arc::clone(&server);
+        warp::patch()
+            .and(warp::path("docs"))
+            .and(doc_id())
+            .and(warp::path::end())
+            .and(writable)
+            .and(warp::body::json())
+            .and(warp::any().map(move || Arc::clone(&server_clone)))
+            .and_then(|id, patch, server: Arc<Server>| server.patch_document(id, patch))
+    };
+
+    let update_document = {
+        let server_clone = Arc::clone(&server);
+        warp::put()
+            .and(warp::path("docs"))
+            .and(doc_id())
+            .and(warp::path::end())
+            .and(writable)
+            .and(warp::query::<HashMap<String, String>>())
+            .and(warp::body::json())
+            .and(warp::any().map(move || Arc::clone(&server_clone)))
+            .and_then(
+                |id, query: HashMap<String, String>, document, server: Arc<Server>| {
+                    let upsert = query.get("upsert").is_some_and(|v| v == "true");
+                    server.update_document(id, upsert, document)
+                },
+            )
+    };
 
-fn lex_string(input: &str) -> Result<(&str, &str), &str> {
-    let input = input.trim_start();
-    if input.starts_with('"') {
-        let end = input[1..]
-            .find('"')
-            .ok_or("Expected end of quoted string")?
-            + 1;
-        let s = &input[1..end];
-        let remaining = &input[end + 1..];
-        Ok((s, remaining))
-    } else {
-        let end = input
-            .find(|c: char| !c.is_alphanumeric() && c != '.')
-            .unwrap_or_else(|| input.len());
-        if end == 0 {
-            Err("No string found")
-        } else {
-            Ok((&input[..end], &input[end..]))
-        }
-    }
-}
+    let put_blob = {
+        let server_clone = Arc::clone(&server);
+        warp::put()
+            .and(warp::path("kv"))
+            .and(doc_id())
+            .and(warp::path::end())
+            .and(writable)
+            .and(warp::body::bytes())
+            .and(warp::any().map(move || Arc::clone(&server_clone)))
+            .and_then(|key, body, server: Arc<Server>| server.put_blob(key, body))
+    };
 
-fn parse_query(q: &str) -> Result<Query, &str> {
-    let mut query = q.trim_start();
-    let mut parsed = Query { conditions: vec![] };
+    let get_blob = {
+        let server_clone = Arc::clone(&server);
+        warp::get()
+            .and(warp::path("kv"))
+            .and(doc_id())
+            .and(warp::path::end())
+            .and(warp::any().map(move || Arc::clone(&server_clone)))
+            .and_then(|key, server: Arc<Server>| server.get_blob(key))
+    };
 
-    while !query.is_empty() {
-        let (key, remaining) = lex_string(query)?;
-        query = remaining.trim_start();
+    let distinct_values = {
+        let server_clone = Arc::clone(&server);
+        warp::get()
+            .and(warp::path("docs"))
+            .and(warp::path("distinct"))
+            .and(warp::path::end())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(warp::any().map(move || Arc::clone(&server_clone)))
+            .and_then(|query: HashMap<String, String>, server: Arc<Server>| {
+                let with_counts = query.get("counts").is_some_and(|v| v == "true");
+                server.distinct_values(query.get("field").cloned(), with_counts)
+            })
+    };
 
-        if !query.starts_with(':') {
-            return Err("Expected colon");
-        }
-        query = query[1..].trim_start();
+    let schema = {
+        let server_clone = Arc::clone(&server);
+        warp::get()
+            .and(warp::path("docs"))
+            .and(warp::path("schema"))
+            .and(warp::path::end())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(warp::any().map(move || Arc::clone(&server_clone)))
+            .and_then(|query: HashMap<String, String>, server: Arc<Server>| {
+                server.schema(query.get("sample").cloned())
+            })
+    };
 
-        let op = match query.chars().next() {
-            Some('>') | Some('<') => {
-                let op = query[0..1].to_string();
-                query = query[1..].trim_start();
-                op
-            }
-            _ => "=".to_string(),
-        };
+    let explain = {
+        let server_clone = Arc::clone(&server);
+        warp::get()
+            .and(warp::path("docs"))
+            .and(warp::path("explain"))
+            .and(warp::path::end())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(warp::any().map(move || Arc::clone(&server_clone)))
+            .and_then(|query, server: Arc<Server>| server.explain(query))
+    };
 
-        let (value, remaining) = lex_string(query)?;
-        query = remaining.trim_start();
+    let documents_size = {
+        let server_clone = Arc::clone(&server);
+        warp::get()
+            .and(warp::path("docs"))
+            .and(warp::path("size"))
+            .and(warp::path::end())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(warp::any().map(move || Arc::clone(&server_clone)))
+            .and_then(|query, server: Arc<Server>| server.documents_size(query))
+    };
 
-        let key = key.split('.').map(|s| s.to_owned()).collect();
-        let argument = QueryCondition::new(key, value.to_owned(), op);
-        parsed.conditions.push(argument);
-    }
+    let get_document = {
+        let server_clone = Arc::clone(&server);
+        warp::get()
+            .and(warp::path("docs"))
+            .and(doc_id())
+            .and(warp::path::end())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(warp::header::optional::<String>("if-modified-since"))
+            .and(warp::any().map(move || Arc::clone(&server_clone)))
+            .and_then(
+                |id, query: HashMap<String, String>, since, server: Arc<Server>| {
+                    let include_deleted = query.get("include_deleted").is_some_and(|v| v == "true");
+                    server.get_document(id, include_deleted, since)
+                },
+            )
+    };
 
-    Ok(parsed)
-}
+    let list_versions = {
+        let server_clone = Arc::clone(&server);
+        warp::get()
+            .and(warp::path("docs"))
+            .and(doc_id())
+            .and(warp::path("versions"))
+            .and(warp::path::end())
+            .and(warp::any().map(move || Arc::clone(&server_clone)))
+            .and_then(|id, server: Arc<Server>| server.list_versions(id))
+    };
+
+    let get_document_field = {
+        let server_clone = Arc::clone(&server);
+        warp::get()
+            .and(warp::path("docs"))
+            .and(doc_id())
+            .and(warp::path("field"))
+            .and(warp::path::end())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(warp::any().map(move || Arc::clone(&server_clone)))
+            .and_then(|id, query: HashMap<String, String>, server: Arc<Server>| {
+                server.get_document_field(id, query.get("path").cloned())
+            })
+    };
 
-#[tokio::main]
-async fn main() {
-    let server = Arc::new(Server::new("docdb.data", "8080").unwrap());
-    let port = server.port.clone();
+    let document_modified = {
+        let server_clone = Arc::clone(&server);
+        warp::get()
+            .and(warp::path("docs"))
+            .and(doc_id())
+            .and(warp::path("modified"))
+            .and(warp::path::end())
+            .and(warp::any().map(move || Arc::clone(&server_clone)))
+            .and_then(|id, server: Arc<Server>| server.document_modified(id))
+    };
 
-    let add_document = {
+    let get_documents = {
         let server_clone = Arc::clone(&server);
         warp::post()
             .and(warp::path("docs"))
+            .and(warp::path("mget"))
+            .and(warp::path::end())
+            .and(warp::query::<HashMap<String, String>>())
             .and(warp::body::json())
             .and(warp::any().map(move || Arc::clone(&server_clone)))
-            .and_then(|document, server: Arc<Server>| server.add_document(document))
+            .and_then(
+                |query: HashMap<String, String>, request, server: Arc<Server>| {
+                    let strict = query.get("strict").is_some_and(|v| v == "true");
+                    server.get_documents(request, strict)
+                },
+            )
     };
 
-    let get_document = {
+    let search_documents_by_body = {
         let server_clone = Arc::clone(&server);
-        warp::get()
+        warp::post()
             .and(warp::path("docs"))
-            .and(warp::path::param())
+            .and(warp::path("search"))
+            .and(warp::path::end())
+            .and(warp::body::json())
             .and(warp::any().map(move || Arc::clone(&server_clone)))
-            .and_then(|id, server: Arc<Server>| server.get_document(id))
+            .and_then(|request, server: Arc<Server>| server.search_documents_by_body(request))
     };
 
     let search_documents = {
         let server_clone = Arc::clone(&server);
         warp::get()
             .and(warp::path("docs"))
+            .and(warp::path::end())
+            // a list, since `q` may be repeated; other params keep their last value
+            .and(warp::query::<Vec<(String, String)>>())
+            .and(warp::header::optional::<String>("accept"))
+            .map(
+                move |pairs: Vec<(String, String)>, accept: Option<String>| {
+                    // Move cloned server reference into this closure
+                    let server_ref = Arc::clone(&server_clone);
+                    let queries = pairs
+                        .iter()
+                        .filter(|(name, _)| name == "q")
+                        .map(|(_, q)| q.clone())
+                        .collect::<Vec<String>>();
+                    let query = pairs.into_iter().collect::<HashMap<String, String>>();
+                    let format = ResultFormat::negotiate(
+                        query.get("format"),
+                        accept.as_ref(),
+                        server_ref.config.default_format,
+                    );
+                    (server_ref, query, queries, format)
+                },
+            )
+            .and_then(
+                |(server, query, queries, format): (
+                    Arc<Server>,
+                    HashMap<String, String>,
+                    Vec<String>,
+                    Option<ResultFormat>,
+                )| async move { server.search_documents(query, queries, format).await },
+            )
+    };
+
+    let compact = {
+        let server_clone = Arc::clone(&server);
+        warp::post()
+            .and(warp::path("admin"))
+            .and(warp::path("compact"))
+            .and(warp::path::end())
+            .and(admin.clone())
+            .and(writable)
+            .and(warp::any().map(move || Arc::clone(&server_clone)))
+            .and_then(|server: Arc<Server>| server.compact())
+    };
+
+    let flush = {
+        let server_clone = Arc::clone(&server);
+        warp::post()
+            .and(warp::path("admin"))
+            .and(warp::path("flush"))
+            .and(warp::path::end())
+            .and(admin.clone())
+            .and(writable)
+            .and(warp::any().map(move || Arc::clone(&server_clone)))
+            .and_then(|server: Arc<Server>| server.flush())
+    };
+
+    let purge = {
+        let server_clone = Arc::clone(&server);
+        warp::post()
+            .and(warp::path("admin"))
+            .and(warp::path("purge"))
+            .and(warp::path::end())
+            .and(admin.clone())
+            .and(writable)
             .and(warp::query::<HashMap<String, String>>())
-            .map(move |query: HashMap<String, String>| {
-                // Move cloned server reference into this closure
-                let server_ref = Arc::clone(&server_clone);
-                let q = query.get("q").unwrap_or(&"".to_string()).clone();
-                (server_ref, q)
-            })
-            .and_then(|(server, q): (Arc<Server>, String)| async move {
-                server.search_documents(&q).await
+            .and(warp::any().map(move || Arc::clone(&server_clone)))
+            .and_then(|query: HashMap<String, String>, server: Arc<Server>| {
+                server.purge(query.get("before").cloned())
             })
     };
 
-    let routes = add_document.or(get_document).or(search_documents);
+    let import_file = {
+        let server_clone = Arc::clone(&server);
+        warp::post()
+            .and(warp::path("admin"))
+            .and(warp::path("import-file"))
+            .and(warp::path::end())
+            .and(admin.clone())
+            .and(writable)
+            .and(warp::body::json())
+            .and(warp::any().map(move || Arc::clone(&server_clone)))
+            .and_then(|request, server: Arc<Server>| server.import_file(request))
+    };
+
+    let audit_log = {
+        let server_clone = Arc::clone(&server);
+        warp::get()
+            .and(warp::path("admin"))
+            .and(warp::path("audit"))
+            .and(warp::path::end())
+            .and(admin.clone())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(warp::any().map(move || Arc::clone(&server_clone)))
+            .and_then(|query, server: Arc<Server>| server.audit_log(query))
+    };
+
+    let rocksdb_properties = {
+        let server_clone = Arc::clone(&server);
+        warp::get()
+            .and(warp::path("admin"))
+            .and(warp::path("rocksdb-props"))
+            .and(warp::path::end())
+            .and(admin)
+            .and(warp::any().map(move || Arc::clone(&server_clone)))
+            .and_then(|server: Arc<Server>| server.rocksdb_properties())
+    };
+
+    let version = {
+        let server_clone = Arc::clone(&server);
+        warp::get()
+            .and(warp::path("version"))
+            .and(warp::path::end())
+            .and(warp::any().map(move || Arc::clone(&server_clone)))
+            .and_then(|server: Arc<Server>| server.version())
+    };
+
+    // the process is up and serving requests, nothing else is checked
+    let livez = warp::get()
+        .and(warp::path("livez"))
+        .and(warp::path::end())
+        .map(|| {
+            let status = StatusCode::OK;
+            reply::with_status(reply::json(&json!({ "status": status.as_str() })), status)
+        });
+
+    let readyz = {
+        let server_clone = Arc::clone(&server);
+        warp::get()
+            .and(warp::path("readyz"))
+            .and(warp::path::end())
+            .and(warp::any().map(move || Arc::clone(&server_clone)))
+            .and_then(|server: Arc<Server>| server.readiness())
+    };
+
+    // a trailing slash already matches ("/docs/" is "/docs"), duplicate slashes are
+    // redirected once nothing else matched
+    let normalize_path = warp::path::full()
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and_then(redirect_to_canonical);
+
+    let unknown_route = {
+        let prefix = server.config.route_prefix.clone();
+        warp::method()
+            .and(warp::path::full())
+            .and_then(move |method, path| route_not_found(prefix.clone(), method, path))
+    };
+
+    // ROUTE_PREFIX, one path segment at a time
+    let prefix = server
+        .config
+        .route_prefix
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .fold(warp::any().boxed(), |prefix, segment| {
+            prefix.and(warp::path(segment.to_string())).boxed()
+        });
+
+    let write_routes = add_document
+        .or(delete_documents)
+        .or(incr_document)
+        .or(restore_document)
+        .or(patch_document)
+        .or(update_document)
+        .or(put_blob)
+        .with(cors(
+            &server.config.cors_write_origins,
+            &["POST", "PUT", "PATCH", "DELETE"],
+        ));
+
+    // distinct_values, schema, explain and documents_size go before
+    // get_document, which would take their names for an id
+    let read_routes = distinct_values
+        .or(schema)
+        .or(explain)
+        .or(documents_size)
+        .or(get_document)
+        .or(get_document_field)
+        .or(document_modified)
+        .or(list_versions)
+        .or(search_documents)
+        .or(search_documents_by_body)
+        .or(get_documents)
+        .or(get_blob)
+        .with(cors(&server.config.cors_read_origins, &["GET", "POST"]));
+
+    let limited_routes = write_routes
+        .or(read_routes)
+        .or(compact)
+        .or(flush)
+        .or(purge)
+        .or(import_file)
+        .or(rocksdb_properties)
+        .or(audit_log)
+        .or(version);
+
+    // probes come often and from one address, so they aren't limited.
+    // everything else takes one token, before the routes and the fallbacks
+    // that a request rejected by a route falls through to. the fallbacks see
+    // the full path, prefix included
+    let routes = prefix
+        .clone()
+        .and(livez.or(readyz))
+        .or(rate_limit.and(
+            prefix
+                .and(limited_routes)
+                .or(normalize_path.or(unknown_route)),
+        ))
+        .recover(handle_rejection);
+
+    // one `request` line per response, with the same fields every time.
+    // `bytes` is null for a streamed body, whose size isn't known up front
+    let routes = warp::any()
+        .map(Instant::now)
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(warp::addr::remote())
+        .and(routes)
+        .map(
+            |start: Instant,
+             method: Method,
+             path: warp::path::FullPath,
+             remote: Option<SocketAddr>,
+             reply| {
+                let response = Reply::into_response(reply);
+                let bytes = warp::hyper::body::HttpBody::size_hint(response.body()).exact();
+                let client_ip = remote.map(|addr| addr.ip().to_string());
+                tracing::info!(
+                    method = %method,
+                    path = path.as_str(),
+                    status = response.status().as_u16(),
+                    bytes,
+                    duration_ms = start.elapsed().as_secs_f64() * 1000.0,
+                    client_ip = client_ip.as_deref(),
+                    "request"
+                );
+                response
+            },
+        );
+
+    // all listeners stop accepting on ctrl-c and finish their open requests
+    let (shutdown, _) = tokio::sync::broadcast::channel::<()>(1);
+    let mut listeners = Vec::new();
+    let mut addresses = Vec::new();
+    for addr in &server.config.listen_addrs {
+        let mut stop = shutdown.subscribe();
+        let signal = async move {
+            let _ = stop.recv().await;
+        };
+        // warp panics on a certificate or key it can't use
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &server.config.tls {
+            let (addr, listener) = warp::serve(routes.clone())
+                .tls()
+                .cert(&tls.cert)
+                .key(&tls.key)
+                .bind_with_graceful_shutdown(*addr, signal);
+            addresses.push(format!("https://{}", addr));
+            listeners.push(tokio::spawn(listener));
+            continue;
+        }
+        let (addr, listener) = warp::serve(routes.clone())
+            .try_bind_with_graceful_shutdown(*addr, signal)
+            .unwrap_or_else(|e| panic!("failed to listen on {}: {}", addr, e));
+        addresses.push(addr.to_string());
+        listeners.push(tokio::spawn(listener));
+    }
+
+    tracing::info!(
+        addresses = ?addresses,
+        read_only = server.config.read_only,
+        max_open_files = server.config.max_open_files,
+        bulk_load = server.bulk_load.load(AtomicOrdering::SeqCst),
+        compaction_style = ?server.config.compaction_style,
+        num_levels = ?server.config.num_levels,
+        target_file_size_base = ?server.config.target_file_size_base,
+        level0_compaction_trigger = ?server.config.level0_compaction_trigger,
+        soft_delete = server.config.soft_delete,
+        route_prefix = %server.config.route_prefix,
+        "listening"
+    );
+
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        tracing::error!(error = %e, "failed to wait for ctrl-c");
+    }
+    tracing::info!("shutting down");
+    let _ = shutdown.send(());
+    for listener in listeners {
+        let _ = listener.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(q: &str) -> Query {
+        parse_query(q, DEFAULT_MAX_VALUE_LENGTH).unwrap()
+    }
+
+    #[test]
+    fn conditions_on_one_field_make_a_range() {
+        let q = query("price:>10 price:<100");
+        assert!(q.matches(&json!({ "price": 50 })));
+        assert!(q.matches(&json!({ "price": 10.5 })));
+        assert!(q.matches(&json!({ "price": 99.99 })));
+        // both bounds are exclusive
+        assert!(!q.matches(&json!({ "price": 10 })));
+        assert!(!q.matches(&json!({ "price": 100 })));
+        assert!(!q.matches(&json!({ "price": 5 })));
+        assert!(!q.matches(&json!({ "price": 1000 })));
+        assert!(!q.matches(&json!({ "price": "cheap" })));
+        assert!(!q.matches(&json!({ "price": null })));
+        assert!(!q.matches(&json!({})));
+    }
+
+    #[test]
+    fn negated_equality_on_arrays() {
+        let q = query("!tags:=archived");
+        assert!(!q.matches(&json!({ "tags": ["new", "archived"] })));
+        assert!(!q.matches(&json!({ "tags": "archived" })));
+        assert!(q.matches(&json!({ "tags": ["new"] })));
+        assert!(q.matches(&json!({ "tags": [] })));
+        assert!(q.matches(&json!({})));
+        assert!(query("tags:=archived").matches(&json!({ "tags": ["archived"] })));
+        assert!(query("years:1999").matches(&json!({ "years": [1998, 1999.0] })));
+        // a json literal still compares the whole array
+        assert!(!query("tags:=[\"archived\"]").matches(&json!({ "tags": ["new", "archived"] })));
+    }
+
+    #[test]
+    fn array_elements_are_indexed() {
+        let doc = json!({ "tags": ["b", "a", "b", true, 3, null, ["c"], { "d": 1 }] });
+        let keys = get_path_values(&doc, String::new());
+        assert_eq!(
+            keys,
+            [
+                ".tags:\"a\"",
+                ".tags:\"b\"",
+                ".tags:3",
+                ".tags:null",
+                ".tags:true",
+            ]
+        );
+        // the keys an indexed lookup reads are among them
+        let lookups = query("tags:=b").index_lookups();
+        assert_eq!(lookups, [[".tags:\"b\"".to_string()]]);
+    }
+
+    #[test]
+    fn byte_budget_keeps_one_entry_past_the_page() {
+        let entry = json!({ "id": "a", "body": { "n": 1 } });
+        // the entry and its newline, as trimming counts them
+        let size = entry.to_string().len() + 1;
+        let mut budget = ByteBudget::new(1, size);
+        let mut documents = Vec::new();
+        let mut stops = Vec::new();
+        for _ in 0..4 {
+            documents.push(entry.clone());
+            stops.push(budget.spend(&documents));
+        }
+        // the skipped match is free, the second page entry is the one over
+        assert_eq!(stops, [false, false, true, true]);
+        assert!(budget.is_spent());
+        // a page whose first entry alone is over the budget still scans on
+        let mut budget = ByteBudget::new(0, 1);
+        assert!(!budget.spend(&documents[..1]));
+        assert!(budget.is_spent());
+    }
+
+    #[test]
+    fn json_literals_normalize_apart_from_strings() {
+        let literal = query("tags:=[1]").normalized();
+        assert_ne!(literal, query("tags:=\"[1]\"").normalized());
+        assert_ne!(literal, query("tags:=[2]").normalized());
+        assert_eq!(literal, query("tags:=[ 1 ]").normalized());
+    }
+
+    #[test]
+    fn history_keys_are_told_apart_from_posting_lists() {
+        assert!(is_history_key(history_key(".a", 7).as_bytes()));
+        // a field name with a `\0`, even followed by digits, is still indexed
+        let doc = json!({ "a\u{0}00000000000000000007": 1, "b\u{0}c": "\u{0}" });
+        for key in get_path_values(&doc, String::new()) {
+            assert!(!is_history_key(key.as_bytes()), "{:?}", key);
+        }
+    }
+
+    // the examples of RFC 7386, appendix A: (original, patch, result)
+    #[test]
+    fn merge_patch_rfc_7386_examples() {
+        let examples = [
+            (json!({"a": "b"}), json!({"a": "c"}), json!({"a": "c"})),
+            (
+                json!({"a": "b"}),
+                json!({"b": "c"}),
+                json!({"a": "b", "b": "c"}),
+            ),
+            (json!({"a": "b"}), json!({"a": null}), json!({})),
+            (
+                json!({"a": "b", "b": "c"}),
+                json!({"a": null}),
+                json!({"b": "c"}),
+            ),
+            (json!({"a": ["b"]}), json!({"a": "c"}), json!({"a": "c"})),
+            (json!({"a": "c"}), json!({"a": ["b"]}), json!({"a": ["b"]})),
+            (
+                json!({"a": {"b": "c"}}),
+                json!({"a": {"b": "d", "c": null}}),
+                json!({"a": {"b": "d"}}),
+            ),
+            (
+                json!({"a": [{"b": "c"}]}),
+                json!({"a": [1]}),
+                json!({"a": [1]}),
+            ),
+            (json!(["a", "b"]), json!(["c", "d"]), json!(["c", "d"])),
+            (json!({"a": "b"}), json!(["c"]), json!(["c"])),
+            (json!({"a": "foo"}), json!(null), json!(null)),
+            (json!({"a": "foo"}), json!("bar"), json!("bar")),
+            (
+                json!({"e": null}),
+                json!({"a": 1}),
+                json!({"e": null, "a": 1}),
+            ),
+            (
+                json!([1, 2]),
+                json!({"a": "b", "c": null}),
+                json!({"a": "b"}),
+            ),
+            (
+                json!({}),
+                json!({"a": {"bb": {"ccc": null}}}),
+                json!({"a": {"bb": {}}}),
+            ),
+        ];
+        for (original, patch, result) in examples {
+            let mut target = original.clone();
+            merge_patch(&mut target, &patch);
+            assert_eq!(target, result, "{} patched with {}", original, patch);
+        }
+    }
+
+    // a server on a new database in the temp dir, removed again on drop
+    struct TestServer {
+        server: Arc<Server>,
+        path: PathBuf,
+    }
+
+    impl TestServer {
+        fn new() -> Self {
+            let path = env::temp_dir().join(format!("erustic_search-{}", Uuid::new_v4()));
+            let server = Server::new(path.to_str().unwrap(), Config::load()).unwrap();
+            Self {
+                server: Arc::new(server),
+                path,
+            }
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.path);
+            let _ = fs::remove_dir_all(self.path.with_extension("index"));
+        }
+    }
+
+    fn path(path: &str) -> Vec<String> {
+        split_path(path, '.')
+    }
+
+    #[test]
+    fn length_of_arrays() {
+        for n in [0, 1, 3, 10] {
+            let doc = json!({ "tags": vec!["t"; n] });
+            assert_eq!(get_value_from_doc(doc, &path("tags.length")), json!(n));
+        }
+        let q = query("tags.length:>3");
+        assert!(q.matches(&json!({ "tags": ["a", "b", "c", "d"] })));
+        assert!(!q.matches(&json!({ "tags": ["a", "b", "c"] })));
+        assert!(!q.matches(&json!({ "tags": [] })));
+    }
+
+    #[test]
+    fn length_of_non_arrays() {
+        // numbers, booleans and null have no length
+        for value in [json!(5), json!(true), json!(null)] {
+            let doc = json!({ "tags": value });
+            assert_eq!(get_value_from_doc(doc, &path("tags.length")), Value::Null);
+        }
+        assert_eq!(
+            get_value_from_doc(json!({}), &path("tags.length")),
+            Value::Null
+        );
+        assert!(!query("tags.length:>0").matches(&json!({ "tags": 5 })));
+        // nothing is below the virtual field
+        let doc = json!({ "tags": ["a"] });
+        assert_eq!(get_value_from_doc(doc, &path("tags.length.x")), Value::Null);
+    }
+
+    #[test]
+    fn length_field_of_an_object() {
+        let doc = json!({ "box": { "length": 7 } });
+        assert_eq!(
+            get_value_from_doc(doc.clone(), &path("box.length")),
+            json!(7)
+        );
+        assert!(query("box.length:>5").matches(&doc));
+        let doc = json!({ "box": {} });
+        assert_eq!(get_value_from_doc(doc, &path("box.length")), Value::Null);
+    }
+
+    #[test]
+    fn integers_beyond_2_53_compare_exactly() {
+        // both are the same f64, 2^53
+        let larger = json!(9007199254740993u64);
+        assert_eq!(
+            compare_numbers(&larger, "9007199254740992"),
+            Some(Ordering::Greater)
+        );
+        let smaller = json!(9007199254740992u64);
+        assert_eq!(
+            compare_numbers(&smaller, "9007199254740993"),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            compare_numbers(&larger, "9007199254740993"),
+            Some(Ordering::Equal)
+        );
+        assert!(!values_equal(
+            &larger,
+            "9007199254740992",
+            DEFAULT_FLOAT_EPSILON
+        ));
+        assert!(values_equal(
+            &larger,
+            "9007199254740993",
+            DEFAULT_FLOAT_EPSILON
+        ));
+        let doc = json!({ "id": 9007199254740993u64 });
+        assert!(!query("id:=9007199254740992").matches(&doc));
+        assert!(query("id:>9007199254740992").matches(&doc));
+        assert!(!query("id:<9007199254740993").matches(&doc));
+    }
+
+    fn aggregate(name: &str, items: Value) -> Value {
+        let items = items.as_array().unwrap().clone();
+        Aggregate::from_name(name).unwrap().apply(&items)
+    }
+
+    #[test]
+    fn aggregates_of_numbers() {
+        let numbers = json!([3, -1, 7.5, 2]);
+        assert_eq!(aggregate("max", numbers.clone()), json!(7.5));
+        assert_eq!(aggregate("min", numbers.clone()), json!(-1));
+        assert_eq!(aggregate("avg", numbers.clone()), json!(2.875));
+        assert_eq!(aggregate("sum", numbers), json!(11.5));
+        // max and min keep the element, the sum of integers stays an integer
+        assert_eq!(aggregate("max", json!([1, 9, 4])), json!(9));
+        assert_eq!(aggregate("sum", json!([1, 9, 4])), json!(14));
+        assert!(aggregate("sum", json!([1, 9, 4])).is_i64());
+        assert_eq!(aggregate("avg", json!([1, 2])), json!(1.5));
+        // exact beyond 2^53, where an f64 sum would round
+        let big = json!([9007199254740993i64, 1]);
+        assert_eq!(aggregate("sum", big), json!(9007199254740994i64));
+        assert_eq!(aggregate("max", json!([5])), json!(5));
+    }
+
+    #[test]
+    fn aggregates_of_empty_arrays() {
+        assert_eq!(aggregate("max", json!([])), Value::Null);
+        assert_eq!(aggregate("min", json!([])), Value::Null);
+        assert_eq!(aggregate("avg", json!([])), Value::Null);
+        assert_eq!(aggregate("sum", json!([])), json!(0));
+    }
+
+    #[test]
+    fn aggregates_of_non_numbers() {
+        for items in [
+            json!([1, "2", 3]),
+            json!([1, null]),
+            json!([[1], 2]),
+            json!(["a"]),
+        ] {
+            for name in ["max", "min", "avg", "sum"] {
+                assert_eq!(
+                    aggregate(name, items.clone()),
+                    Value::Null,
+                    "{} of {}",
+                    name,
+                    items
+                );
+            }
+        }
+        assert!(Aggregate::from_name("median").is_none());
+        // through a path, and only on arrays
+        let doc = json!({ "scores": [80, 95, 70], "total": 5 });
+        assert_eq!(
+            get_value_from_doc(doc.clone(), &path("scores.max")),
+            json!(95)
+        );
+        assert_eq!(
+            get_value_from_doc(doc.clone(), &path("total.max")),
+            Value::Null
+        );
+        assert!(query("scores.avg:>80").matches(&doc));
+        assert!(!query("scores.min:>70").matches(&doc));
+    }
+
+    #[tokio::test]
+    async fn non_object_documents_are_rejected() {
+        let test = TestServer::new();
+        for body in [
+            json!([1, 2]),
+            json!("text"),
+            json!(null),
+            json!(42),
+            json!(true),
+        ] {
+            let server = Arc::clone(&test.server);
+            let response = server.add_document(body.clone(), None).await.unwrap();
+            assert_eq!(response.into_response().status(), StatusCode::BAD_REQUEST);
+
+            let server = Arc::clone(&test.server);
+            let response = server
+                .update_document("doc".to_string(), true, body.clone())
+                .await
+                .unwrap();
+            assert_eq!(response.into_response().status(), StatusCode::BAD_REQUEST);
+
+            let server = Arc::clone(&test.server);
+            let response = server
+                .patch_document("doc".to_string(), body)
+                .await
+                .unwrap();
+            assert_eq!(response.into_response().status(), StatusCode::BAD_REQUEST);
+        }
+        // nothing was stored, not even by the upserts
+        assert!(test
+            .server
+            .get_document_by_id("doc".to_string())
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn lock_shard_is_deterministic() {
+        let test = TestServer::new();
+        let other = TestServer::new();
+        for id in ["doc", "", "a-much-longer-document-id", "\u{e9}t\u{e9}"] {
+            let shard = test.server.lock_shard(id);
+            assert!(shard < LOCK_SHARDS);
+            assert_eq!(test.server.lock_shard(id), shard);
+            // the hasher isn't seeded per process or per server
+            assert_eq!(other.server.lock_shard(id), shard);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_writes_to_one_id_serialize() {
+        let test = TestServer::new();
+        let server = Arc::clone(&test.server);
+        let document = json!({ "count": 0 });
+        server
+            .update_document("doc".to_string(), true, document)
+            .await
+            .unwrap();
 
-    println!("Listening on port {}", port);
+        const WRITERS: usize = 50;
+        let mut tasks = Vec::new();
+        for i in 0..WRITERS {
+            let server = Arc::clone(&test.server);
+            tasks.push(tokio::spawn(async move {
+                let request = IncrRequest {
+                    field: "count".to_string(),
+                    by: Number::from(1),
+                };
+                let response = Arc::clone(&server)
+                    .incr_document("doc".to_string(), request)
+                    .await
+                    .unwrap();
+                assert_eq!(response.into_response().status(), StatusCode::OK);
+                // a patch reads the document and writes it back whole, so one
+                // that raced another would lose its key
+                let patch = json!({ format!("key{}", i): i });
+                let response = server
+                    .patch_document("doc".to_string(), patch)
+                    .await
+                    .unwrap();
+                assert_eq!(response.into_response().status(), StatusCode::OK);
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
 
-    warp::serve(routes).run(([127, 0, 0, 1], 8080)).await;
+        let doc = test
+            .server
+            .get_document_by_id("doc".to_string())
+            .unwrap()
+            .unwrap();
+        assert_eq!(doc["count"], json!(WRITERS));
+        for i in 0..WRITERS {
+            assert_eq!(doc[format!("key{}", i)], json!(i));
+        }
+    }
 }